@@ -0,0 +1,123 @@
+//! C ABI over the Pak API.
+//!
+//! Every function returns one of the `RUSTPAK_*` status codes and hands results
+//! back through out-params. A `Pak` obtained from `rustpak_new` or
+//! `rustpak_open` is owned by the caller and must be released with
+//! `rustpak_free`.
+
+use std::{
+    ffi::CStr,
+    os::raw::{c_char, c_int},
+    ptr,
+};
+
+use crate::Pak;
+
+pub const RUSTPAK_OK: c_int = 0;
+/// A required pointer argument was null.
+pub const RUSTPAK_ERR_NULL: c_int = -1;
+/// A string argument was not valid UTF-8.
+pub const RUSTPAK_ERR_UTF8: c_int = -2;
+/// The Pak could not be read or the output could not be written.
+pub const RUSTPAK_ERR_IO: c_int = -3;
+/// No entry with the requested name exists.
+pub const RUSTPAK_ERR_NOT_FOUND: c_int = -4;
+
+unsafe fn to_string(s: *const c_char) -> Result<String, c_int> {
+    if s.is_null() {
+        return Err(RUSTPAK_ERR_NULL);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(|s| s.to_string())
+        .map_err(|_| RUSTPAK_ERR_UTF8)
+}
+
+/// Allocates an empty Pak.
+#[no_mangle]
+pub extern "C" fn rustpak_new() -> *mut Pak {
+    Box::into_raw(Box::new(Pak::empty()))
+}
+
+/// Loads the Pak at `path` and stores it in `*out`. On failure `*out` is set
+/// to null.
+///
+/// # Safety
+///
+/// `path` must be a valid NUL-terminated string and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rustpak_open(path: *const c_char, out: *mut *mut Pak) -> c_int {
+    if out.is_null() {
+        return RUSTPAK_ERR_NULL;
+    }
+    *out = ptr::null_mut();
+
+    let path = match to_string(path) {
+        Ok(path) => path,
+        Err(code) => return code,
+    };
+    match Pak::from_file(path) {
+        Ok(pak) => {
+            *out = Box::into_raw(Box::new(pak));
+            RUSTPAK_OK
+        }
+        Err(_) => RUSTPAK_ERR_IO,
+    }
+}
+
+/// Stores the number of entries of `pak` in `*out`.
+///
+/// # Safety
+///
+/// `pak` must come from `rustpak_new`/`rustpak_open` and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rustpak_entry_count(pak: *const Pak, out: *mut usize) -> c_int {
+    if pak.is_null() || out.is_null() {
+        return RUSTPAK_ERR_NULL;
+    }
+    *out = (*pak).files.len();
+    RUSTPAK_OK
+}
+
+/// Writes the data of entry `name` to the file at `dest`, creating parent
+/// directories as needed. An existing file is only replaced if `overwrite` is set.
+///
+/// # Safety
+///
+/// `pak` must come from `rustpak_new`/`rustpak_open`, `name` and `dest` must be
+/// valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn rustpak_extract(
+    pak: *const Pak,
+    name: *const c_char,
+    dest: *const c_char,
+    overwrite: bool,
+) -> c_int {
+    if pak.is_null() {
+        return RUSTPAK_ERR_NULL;
+    }
+    let (name, dest) = match (to_string(name), to_string(dest)) {
+        (Ok(name), Ok(dest)) => (name, dest),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+
+    match (*pak).find(&name) {
+        Some(entry) => match entry.save_to(dest, true, overwrite) {
+            Ok(_) => RUSTPAK_OK,
+            Err(_) => RUSTPAK_ERR_IO,
+        },
+        None => RUSTPAK_ERR_NOT_FOUND,
+    }
+}
+
+/// Releases a Pak. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `pak` must come from `rustpak_new`/`rustpak_open` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rustpak_free(pak: *mut Pak) {
+    if !pak.is_null() {
+        drop(Box::from_raw(pak));
+    }
+}
@@ -0,0 +1,156 @@
+//! Formatting of file listings for the `list` subcommand.
+
+use std::{io, str::FromStr};
+
+use crate::PakFileEntry;
+
+/// Output format of a listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFormat {
+    /// `name - size bytes`, one entry per line.
+    Plain,
+    /// `name,offset,size` rows after a header row.
+    Csv,
+    /// A JSON array with one object per entry.
+    Json,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(ListFormat::Plain),
+            "csv" => Ok(ListFormat::Csv),
+            "json" => Ok(ListFormat::Json),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+/// How to print a list of entries.
+#[derive(Debug, Clone, Copy)]
+pub struct Listing {
+    pub format: ListFormat,
+    /// Adds each entry's CRC32.
+    pub checksum: bool,
+    /// Adds `text` or `binary` as guessed by `PakFileEntry::is_text`.
+    pub kind: bool,
+}
+
+impl Listing {
+    pub fn new(format: ListFormat) -> Listing {
+        Listing {
+            format,
+            checksum: false,
+            kind: false,
+        }
+    }
+
+    pub fn write<'a, W: io::Write>(
+        &self,
+        mut writer: W,
+        files: impl IntoIterator<Item = &'a PakFileEntry>,
+    ) -> io::Result<()> {
+        match self.format {
+            ListFormat::Plain => {
+                for file in files {
+                    write!(writer, "{} - {} bytes", file.name, file.size)?;
+                    if self.checksum {
+                        write!(writer, " - crc32 {:08x}", file.crc32())?;
+                    }
+                    if self.kind {
+                        write!(writer, " - {}", kind(file))?;
+                    }
+                    writeln!(writer)?;
+                }
+            }
+            ListFormat::Csv => {
+                write!(writer, "name,offset,size")?;
+                if self.checksum {
+                    write!(writer, ",crc32")?;
+                }
+                if self.kind {
+                    write!(writer, ",kind")?;
+                }
+                writeln!(writer)?;
+                for file in files {
+                    write!(
+                        writer,
+                        "{},{},{}",
+                        csv_escape(&file.name),
+                        file.offset,
+                        file.size
+                    )?;
+                    if self.checksum {
+                        write!(writer, ",{:08x}", file.crc32())?;
+                    }
+                    if self.kind {
+                        write!(writer, ",{}", kind(file))?;
+                    }
+                    writeln!(writer)?;
+                }
+            }
+            ListFormat::Json => {
+                write!(writer, "[")?;
+                for (i, file) in files.into_iter().enumerate() {
+                    if i > 0 {
+                        write!(writer, ",")?;
+                    }
+                    write!(
+                        writer,
+                        "\n  {{\"name\": {}, \"offset\": {}, \"size\": {}",
+                        json_string(&file.name),
+                        file.offset,
+                        file.size
+                    )?;
+                    if self.checksum {
+                        write!(writer, ", \"crc32\": \"{:08x}\"", file.crc32())?;
+                    }
+                    if self.kind {
+                        write!(writer, ", \"kind\": \"{}\"", kind(file))?;
+                    }
+                    write!(writer, "}}")?;
+                }
+                writeln!(writer, "\n]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn kind(file: &PakFileEntry) -> &'static str {
+    if file.is_text() {
+        "text"
+    } else {
+        "binary"
+    }
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break.
+pub fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Renders `s` as a quoted JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,9 +1,130 @@
 extern crate byteorder;
-use std::{borrow::Borrow, error::Error, fs::{self, File}, io::{self, Read, Seek, SeekFrom}, path};
 
-use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+pub mod ffi;
+pub mod format;
+pub mod shell;
+pub mod watch;
 
-#[derive(Debug)]
+use std::{collections::{BTreeMap, HashMap, HashSet}, error::Error, fs::{self, File}, io::{self, BufWriter, Read, Seek, SeekFrom, Write}, ops::{Deref, Range}, path, sync::{Arc, Mutex}};
+
+use filetime::FileTime;
+use globset::{GlobBuilder, GlobMatcher};
+use rayon::prelude::*;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+
+/// Size of the header: the magic, then the offset and size of the file table.
+pub const HEADER_SIZE: usize = 12;
+/// Size of one file table record: the name field, then the data offset and size.
+pub const ENTRY_SIZE: usize = 64;
+/// Size of the NUL-terminated name field at the start of a table record, so
+/// names can be at most `NAME_SIZE - 1` bytes.
+pub const NAME_SIZE: usize = 56;
+/// The magic at the start of every Pak.
+pub const MAGIC: &[u8; 4] = b"PACK";
+
+/// Byte order of the integer fields in the header and file table. PC Paks
+/// are little-endian; some console ports wrote them big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// Guesses the byte order from the start of a Pak file by checking which
+    /// reading of the header gives a file table that fits inside `bytes`.
+    /// Prefers little-endian when both look sane.
+    pub fn detect(bytes: &[u8]) -> Option<Endian> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        [Endian::Little, Endian::Big].into_iter().find(|endian| {
+            let offset = endian.read_u32(&bytes[4..8]) as u64;
+            let size = endian.read_u32(&bytes[8..HEADER_SIZE]) as u64;
+            offset >= HEADER_SIZE as u64 && size.is_multiple_of(ENTRY_SIZE as u64) && offset + size <= bytes.len() as u64
+        })
+    }
+
+    fn read_u32(self, buf: &[u8]) -> u32 {
+        match self {
+            Endian::Little => LittleEndian::read_u32(buf),
+            Endian::Big => BigEndian::read_u32(buf),
+        }
+    }
+
+    fn write_u32<W: io::Write>(self, writer: &mut W, n: u32) -> io::Result<()> {
+        match self {
+            Endian::Little => writer.write_u32::<LittleEndian>(n),
+            Endian::Big => writer.write_u32::<BigEndian>(n),
+        }
+    }
+}
+
+/// Shape of a file table record: a name field of `name_len` bytes, then the
+/// data offset and size, padded out to `stride` bytes. Standard Paks use
+/// `RecordLayout::STANDARD`; some custom engines use a different name size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLayout {
+    name_len: usize,
+    stride: usize,
+}
+
+impl RecordLayout {
+    /// 56-byte names in 64-byte records.
+    pub const STANDARD: RecordLayout = RecordLayout {
+        name_len: NAME_SIZE,
+        stride: ENTRY_SIZE,
+    };
+
+    /// `None` if the name field is empty or a `stride`-byte record has no
+    /// room for the offset and size after it.
+    pub fn new(name_len: usize, stride: usize) -> Option<RecordLayout> {
+        (name_len > 0 && stride >= name_len + 8).then_some(RecordLayout { name_len, stride })
+    }
+
+    pub fn name_len(&self) -> usize {
+        self.name_len
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Parses one record, which must be at least `stride` bytes. The data is
+    /// left empty for the caller to fill in.
+    fn read_record(&self, record: &[u8], endian: Endian) -> PakFileEntry {
+        let namebuf = &record[0..self.name_len];
+
+        // Most packers NUL-pad the name field, some pad it with spaces instead.
+        let name = match namebuf.iter().position(|&c| c == b'\0') {
+            Some(nul) => &namebuf[..nul],
+            None => {
+                let end = namebuf.iter().rposition(|&c| c != b' ').map_or(0, |last| last + 1);
+                &namebuf[..end]
+            }
+        };
+
+        let offset = endian.read_u32(&record[self.name_len..self.name_len + 4]);
+        let size = endian.read_u32(&record[self.name_len + 4..self.name_len + 8]);
+
+        PakFileEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            offset,
+            size,
+            mtime: None,
+            mode: None,
+            data: EntryData::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 #[repr(C)]
 pub struct PakHeader {
     /// Should be "PACK" (not null-terminated).
@@ -14,249 +135,2642 @@ pub struct PakHeader {
     pub size: u32,   
 }
 
+impl Default for PakHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PakHeader {
     pub fn new() -> PakHeader {
         PakHeader {
-            id: "PACK".to_string(),
+            id: String::from_utf8_lossy(MAGIC).into_owned(),
             offset: 0,
             size: 0,
         }
     }
 
-    pub fn from_u8(buf: &Vec<u8>) -> PakHeader {
-        PakHeader {
-            id: String::from_utf8((&buf[0..4]).to_vec()).unwrap(),
-            offset: LittleEndian::read_u32(&buf[4..8]),
-            size: LittleEndian::read_u32(&buf[8..12]),
+    /// Reads the header from the first 12 bytes of `buf`.
+    pub fn from_u8(buf: &[u8]) -> Result<PakHeader, PakError> {
+        PakHeader::from_u8_endian(buf, Endian::Little)
+    }
+
+    pub fn from_u8_endian(buf: &[u8], endian: Endian) -> Result<PakHeader, PakError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(PakError::Corrupt("file too small for header".to_string()));
+        }
+        Ok(PakHeader {
+            id: String::from_utf8_lossy(&buf[0..4]).into_owned(),
+            offset: endian.read_u32(&buf[4..8]),
+            size: endian.read_u32(&buf[8..HEADER_SIZE]),
+        })
+    }
+
+    /// The 12 header bytes for the current fields, little-endian. The magic is
+    /// cut or NUL-padded to 4 bytes.
+    pub fn as_bytes(&self) -> [u8; HEADER_SIZE] {
+        self.as_bytes_endian(Endian::Little)
+    }
+
+    /// Like `as_bytes`, with the offset and size in `endian` byte order.
+    pub fn as_bytes_endian(&self, endian: Endian) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        let id = self.id.as_bytes();
+        let id_len = id.len().min(MAGIC.len());
+        buf[..id_len].copy_from_slice(&id[..id_len]);
+        // Writing to a slice of the right length can't fail.
+        endian.write_u32(&mut &mut buf[4..8], self.offset).unwrap();
+        endian.write_u32(&mut &mut buf[8..HEADER_SIZE], self.size).unwrap();
+        buf
+    }
+
+    /// Best guess at which game family produced this header, based on the magic.
+    pub fn variant(&self) -> &'static str {
+        if self.id.as_bytes() == MAGIC {
+            "Quake/GoldSrc"
+        } else {
+            "unknown"
         }
     }
 
     #[allow(dead_code)]
-    pub fn write_to<W: io::Write>(&self, mut writer: W) -> Result<(), Box<dyn Error>> {
+    pub fn write_to<W: io::Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        self.write_endian_to(writer, Endian::Little)
+    }
+
+    pub fn write_endian_to<W: io::Write>(&self, mut writer: W, endian: Endian) -> Result<(), Box<dyn Error>> {
         writer.write_all(self.id.as_bytes())?;
-        writer.write_u32::<LittleEndian>(self.offset)?;
-        writer.write_u32::<LittleEndian>(self.size)?;
+        endian.write_u32(&mut writer, self.offset)?;
+        endian.write_u32(&mut writer, self.size)?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
+/// An entry's bytes: a range of a buffer that entries loaded from the same
+/// Pak file share, so loading doesn't copy every entry out of the file.
+/// Without a range the entry has the whole buffer to itself.
+#[derive(Clone, Default)]
+struct EntryData {
+    buf: Arc<Vec<u8>>,
+    range: Option<Range<usize>>,
+}
+
+impl EntryData {
+    fn owned(data: Vec<u8>) -> EntryData {
+        EntryData {
+            buf: Arc::new(data),
+            range: None,
+        }
+    }
+
+    fn shared(buf: &Arc<Vec<u8>>, range: Range<usize>) -> EntryData {
+        EntryData {
+            buf: Arc::clone(buf),
+            range: Some(range),
+        }
+    }
+
+    /// The bytes as a Vec of their own, copying them out of a shared buffer first.
+    fn make_mut(&mut self) -> &mut Vec<u8> {
+        if self.range.is_some() {
+            *self = EntryData::owned(self.to_vec());
+        }
+        Arc::make_mut(&mut self.buf)
+    }
+}
+
+impl Deref for EntryData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.range {
+            Some(range) => &self.buf[range.clone()],
+            None => &self.buf,
+        }
+    }
+}
+
+#[derive(Clone)]
 #[repr(C)]
 pub struct PakFileEntry {
     pub name: String, // 56 byte null-terminated string	Includes path. Example: "maps/e1m1.bsp".
     pub offset: u32, // The offset (from the beginning of the pak file) to the beginning of this file's contents.
     pub size: u32,   // The size of this file.
-    data: Vec<u8>,
+    /// Modification time in unix seconds. Paks don't store one, it only comes
+    /// from a `.times` sidecar or the file the entry was appended from.
+    pub mtime: Option<i64>,
+    /// Unix permission bits. Paks don't store them either, they only come
+    /// from a `.modes` sidecar or the file the entry was appended from.
+    /// Extracting applies only the `0o777` part.
+    pub mode: Option<u32>,
+    data: EntryData,
 }
 
-impl PakFileEntry {
-    pub fn from_u8(header_buf: &Vec<u8>, file_buf: &Vec<u8>) -> PakFileEntry {
-        let namebuf = (&header_buf[0..56]).to_vec();
-
-        let nul_range_end = namebuf
-            .iter()
-            .position(|&c| c == b'\0')
-            .unwrap_or(namebuf.len()); // default to length if no `\0` present
+impl std::fmt::Debug for PakFileEntry {
+    /// Shows only the first bytes of the data so dumping a loaded Pak stays readable.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const PREVIEW: usize = 16;
+        let mut preview: String = self.data.iter().take(PREVIEW).map(|b| format!("{:02x}", b)).collect();
+        if self.data.len() > PREVIEW {
+            preview.push('…');
+        }
 
-        let offset = LittleEndian::read_u32(&header_buf[56..60]);
-        let size = LittleEndian::read_u32(&header_buf[60..64]);
+        f.debug_struct("PakFileEntry")
+            .field("name", &self.name)
+            .field("offset", &self.offset)
+            .field("size", &self.size)
+            .field("mtime", &self.mtime)
+            .field("mode", &self.mode)
+            .field("data", &format_args!("{}", preview))
+            .finish()
+    }
+}
 
-        PakFileEntry {
-            name: String::from_utf8((&header_buf[0..nul_range_end]).to_vec())
-                .unwrap()
-                .trim()
-                .to_string(),
-            offset: offset,
-            size: size,
-            data: (file_buf[offset as usize..(offset + size) as usize]).to_vec(),
+impl PakFileEntry {
+    /// Parses the table record in `header_buf` and copies the entry's data
+    /// out of `file_buf`, the whole Pak file.
+    pub fn from_u8(header_buf: &[u8], file_buf: &[u8]) -> Result<PakFileEntry, PakError> {
+        if header_buf.len() < ENTRY_SIZE {
+            return Err(PakError::Corrupt("table record is cut short".to_string()));
+        }
+        let mut entry = PakFileEntry::from_record(header_buf, Endian::Little);
+        let range = entry.offset as usize..entry.offset as usize + entry.size as usize;
+        match file_buf.get(range) {
+            Some(data) => entry.data = EntryData::owned(data.to_vec()),
+            None => {
+                return Err(PakError::Corrupt(format!("data of {} runs past the end of the file", entry.name)));
+            }
         }
+        Ok(entry)
+    }
+
+    /// Parses a 64-byte table record. The data is left empty for the caller to fill in.
+    fn from_record(header_buf: &[u8], endian: Endian) -> PakFileEntry {
+        RecordLayout::STANDARD.read_record(header_buf, endian)
     }
 
-    pub fn save_to(&self, path: String, with_full_path: bool) -> Result<String, std::io::Error> {
-        let data: &Vec<u8> = self.data.borrow();
+    /// Writes the entry's data to `path`. Refuses to replace an existing file
+    /// unless `overwrite` is set.
+    pub fn save_to(&self, path: String, with_full_path: bool, overwrite: bool) -> Result<String, std::io::Error> {
         let mut path = path::Path::new(&path);
 
         if with_full_path {
-            fs::create_dir_all(path.parent().unwrap())?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
         } else {
-            path = path::Path::new(path.file_name().unwrap().to_str().unwrap())
+            let file_name = path.file_name().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' has no file name", path.display()))
+            })?;
+            path = path::Path::new(file_name);
         }
 
-        std::fs::write(path, data)?;
-        Ok(path.to_str().unwrap().to_string())
+        if !overwrite && path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("'{}' already exists, use --force to overwrite", path.display()),
+            ));
+        }
+
+        std::fs::write(path, self.data())?;
+        if let Some(mtime) = self.mtime {
+            filetime::set_file_mtime(path, FileTime::from_unix_time(mtime, 0))?;
+        }
+        // Only the read/write/execute bits: setuid, setgid and sticky bits
+        // from an untrusted sidecar have no business on extracted files.
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        Ok(path.to_string_lossy().into_owned())
     }
 
     #[allow(dead_code)]
     pub fn new(name: String, offset: u32, data: Vec<u8>) -> PakFileEntry {
         PakFileEntry {
-            name: name,
-            offset: offset,
+            name,
+            offset,
+            mtime: None,
+            mode: None,
             size: data.len() as u32,
-            data: data.to_vec(),
+            data: EntryData::owned(data),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn write_to<W: io::Write>(&self, mut writer: W) -> Result<(), Box<dyn Error>> {
-        let mut buf = self.name.as_bytes().to_vec();
-        //buf.fill_with(self.name.as_bytes());
-        while buf.len() < 56 {
-            buf.push(0 as u8);
+    /// Reads the file at `path` into an entry named `archive_name`, keeping the
+    /// file's modification time and, on Unix, its permissions. The offset is
+    /// left at 0 for `save` to assign.
+    pub fn from_path<P: AsRef<path::Path>>(archive_name: String, path: P) -> Result<PakFileEntry, io::Error> {
+        let path = path.as_ref();
+        let metadata = path.metadata()?;
+        let mut entry = PakFileEntry::new(archive_name, 0, fs::read(path)?);
+        entry.mtime = Some(FileTime::from_last_modification_time(&metadata).unix_seconds());
+        entry.mode = file_mode(&metadata);
+        Ok(entry)
+    }
+
+    /// The entry name for the file at `path` when stored relative to `base`,
+    /// with `/` separators: `maps/e1m1.bsp` for `assets/maps/e1m1.bsp` with
+    /// base `assets`. Fails if `path` isn't below `base`.
+    pub fn name_relative_to<P: AsRef<path::Path>, B: AsRef<path::Path>>(path: P, base: B) -> Result<String, PakError> {
+        let path = path.as_ref();
+        let invalid = || PakError::InvalidName(path.display().to_string());
+        let significant = |p: &path::Path| -> path::PathBuf {
+            p.components().filter(|c| *c != path::Component::CurDir).collect()
+        };
+        let rel = significant(path);
+        let rel = rel.strip_prefix(significant(base.as_ref())).map_err(|_| invalid())?;
+
+        let mut parts: Vec<&str> = Vec::new();
+        for component in rel.components() {
+            match component {
+                path::Component::Normal(part) => parts.push(part.to_str().ok_or_else(invalid)?),
+                _ => return Err(invalid()),
+            }
+        }
+        if parts.is_empty() {
+            return Err(invalid());
+        }
+        Ok(parts.join("/"))
+    }
+
+    /// The entry's data bytes.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The entry's data for editing in place. Data shared with other entries
+    /// is copied first. `size` isn't updated as the data changes, saving
+    /// takes the size from the data instead.
+    pub fn data_mut(&mut self) -> &mut Vec<u8> {
+        self.data.make_mut()
+    }
+
+    /// Whether both entries' data live in the same buffer, as they do for
+    /// entries loaded from the same Pak file.
+    pub fn shares_buffer_with(&self, other: &PakFileEntry) -> bool {
+        Arc::ptr_eq(&self.data.buf, &other.data.buf)
+    }
+
+    /// Writes just the entry's data bytes, as they go into the data area of
+    /// a Pak.
+    pub fn write_data<W: io::Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        writer.write_all(&self.data)
+    }
+
+    #[deprecated(note = "use `write_data`")]
+    pub fn write_data_to<W: io::Write>(&self, writer: W) -> Result<(), std::io::Error> {
+        self.write_data(writer)
+    }
+
+    /// CRC32 (IEEE) of the entry's data.
+    pub fn crc32(&self) -> u32 {
+        crc32fast::hash(&self.data)
+    }
+
+    /// The name with its first `components` directories dropped, e.g.
+    /// `b/c.txt` for `a/b/c.txt` and 1. `None` if nothing would be left.
+    pub fn stripped_name(&self, components: usize) -> Option<&str> {
+        let mut rest = self.name.as_str();
+        for _ in 0..components {
+            rest = rest.split_once('/')?.1;
+        }
+        if rest.is_empty() {
+            None
+        } else {
+            Some(rest)
+        }
+    }
+
+    /// Guesses whether the data is text by looking at its first 1024 bytes:
+    /// no NUL bytes and nearly all of it printable or whitespace. Bytes from
+    /// 0x80 up count as printable so UTF-8 and code page text pass. Empty
+    /// entries count as text.
+    pub fn is_text(&self) -> bool {
+        const SAMPLE: usize = 1024;
+        let sample = &self.data()[..self.data.len().min(SAMPLE)];
+        if sample.contains(&0) {
+            return false;
+        }
+        let control = sample
+            .iter()
+            .filter(|&&b| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c)) || b == 0x7f)
+            .count();
+        control * 20 <= sample.len()
+    }
+
+    /// The entry's data without a leading UTF-8 byte order mark, which some
+    /// game parsers choke on. Data without one is returned as is.
+    pub fn data_without_bom(&self) -> &[u8] {
+        let data = self.data();
+        data.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(data)
+    }
+
+    /// Whether the entry only marks a directory, as some packers write for
+    /// every folder: its name ends with a `/`.
+    pub fn is_dir_marker(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// The part of the file name after the last `.`, if there is one.
+    pub fn extension(&self) -> Option<&str> {
+        let file_name = self.name.rsplit('/').next().unwrap_or(&self.name);
+        match file_name.rsplit_once('.') {
+            Some((_, ext)) if !ext.is_empty() => Some(ext),
+            _ => None,
+        }
+    }
+
+    /// Writes the entry's 64-byte little-endian table record: the
+    /// NUL-padded name followed by the stored offset and size. Fails if the
+    /// name doesn't fit.
+    pub fn write_table_record<W: io::Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        self.write_record_to(writer, self.offset, self.size, Endian::Little)
+    }
+
+    #[deprecated(note = "use `write_table_record`")]
+    pub fn write_to<W: io::Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        self.write_table_record(writer)
+    }
+
+    /// Writes the 64-byte table record, using `offset` and `size` instead of
+    /// the stored ones.
+    fn write_record_to<W: io::Write>(
+        &self,
+        mut writer: W,
+        offset: u32,
+        size: u32,
+        endian: Endian,
+    ) -> Result<(), Box<dyn Error>> {
+        // The name field is always NUL-terminated, so only 55 bytes are usable
+        // and everything after the name is zeroed.
+        let name = self.name.as_bytes();
+        if name.len() >= NAME_SIZE {
+            return Err(Box::new(PakFileError {
+                msg: format!("File name is longer than 55 bytes: {}", self.name),
+            }));
         }
-        writer.write_all(buf.as_slice())?;
-        writer.write_u32::<LittleEndian>(self.offset)?;
-        writer.write_u32::<LittleEndian>(self.size)?;
+        let mut buf = [0u8; NAME_SIZE];
+        buf[..name.len()].copy_from_slice(name);
+        writer.write_all(&buf)?;
+        endian.write_u32(&mut writer, offset)?;
+        endian.write_u32(&mut writer, size)?;
 
         Ok(())
     }
 }
 
-#[derive(Debug)]
+/// Cloning is cheap: entries keep sharing the loaded file's buffer, and an
+/// entry's data is only copied once it is changed through `data_mut`.
+#[derive(Debug, Clone)]
 pub struct Pak {
     pub pak_path: String,
     pub header: PakHeader,
+    /// Entries in table order, which decides which of two same-named entries
+    /// an engine picks. Adding, replacing and removing entries leaves the
+    /// others where they are; only `rebuild` reorders them.
     pub files: Vec<PakFileEntry>,
+    /// Upper bound in bytes for the saved Pak. Saving fails instead of
+    /// producing a bigger file.
+    pub max_size: Option<u64>,
+    /// Byte order used when saving; set from the file by the loaders.
+    pub endian: Endian,
+    /// Makes `add_file`, and the other methods that add or rename entries,
+    /// reject names with bytes outside printable ASCII, which Quake-era tools
+    /// and engines tend to assume.
+    pub ascii_names_only: bool,
+    /// Lets `add_file`, `insert_file_at` and `append_dir` add an entry whose
+    /// name is already taken. Engines
+    /// differ on which copy wins: Quake searches the table from the start and
+    /// uses the first match, while some tools and ports take the last one.
+    /// `find` and the other by-name lookups always see the first.
+    pub allow_duplicates: bool,
+    trailer: Vec<u8>,
+    magic: [u8; 4],
+    table_layout: TableLayout,
+    sha256_index: HashIndexCache,
+}
+
+impl Default for Pak {
+    fn default() -> Self {
+        Self::empty()
+    }
 }
 
 impl Pak {
-    #[allow(dead_code)]
-    #[no_mangle]
-    pub fn new() -> Pak {
+    /// A Pak with no files and no backing path, ready to be filled and saved.
+    pub fn empty() -> Pak {
         Pak {
             pak_path: "".to_string(),
             header: PakHeader::new(),
             files: Vec::new(),
+            max_size: None,
+            endian: Endian::Little,
+            ascii_names_only: false,
+            allow_duplicates: false,
+            trailer: Vec::new(),
+            magic: *MAGIC,
+            table_layout: TableLayout::First,
+            sha256_index: HashIndexCache::default(),
+        }
+    }
+
+    /// Like `empty`, with room for `capacity` files before `files` reallocates.
+    pub fn with_capacity(capacity: usize) -> Pak {
+        Pak {
+            files: Vec::with_capacity(capacity),
+            ..Pak::empty()
         }
     }
 
-    #[no_mangle]
+    /// Reserves room for at least `additional` more files.
+    pub fn reserve(&mut self, additional: usize) {
+        self.files.reserve(additional);
+    }
+
+    #[deprecated(note = "use `Pak::empty`; C callers get a constructor from the FFI layer")]
+    pub fn new() -> Pak {
+        Self::empty()
+    }
+
     pub fn from_file(path: String) -> Result<Pak, Box<dyn Error>> {
-        let bytes = std::fs::read(path.to_string())?;
-        let pakheader = PakHeader::from_u8(&bytes);
-        let num_files = pakheader.size / 64;
+        Pak::from_file_with_endian(path, Endian::Little)
+    }
+
+    /// Like `from_file`, for Paks whose header and table use `endian`.
+    pub fn from_file_with_endian(path: String, endian: Endian) -> Result<Pak, Box<dyn Error>> {
+        let bytes = decompress(std::fs::read(&path)?)?;
+        let mut pak = Pak::parse(path, Arc::new(bytes), endian, RecordLayout::STANDARD, |_| Ok(()))?;
+
+        let times_path = format!("{}.times", pak.pak_path);
+        if path::Path::new(&times_path).exists() {
+            pak.read_times_sidecar(times_path)?;
+        }
+        let modes_path = format!("{}.modes", pak.pak_path);
+        if path::Path::new(&modes_path).exists() {
+            pak.read_modes_sidecar(modes_path)?;
+        }
+        Ok(pak)
+    }
+
+    /// Like `from_file`, but a missing file gives an empty Pak that saves to
+    /// `path` instead of an error. A file that exists but doesn't parse is
+    /// still an error.
+    pub fn open_or_create(path: String) -> Result<Pak, PakError> {
+        if !path::Path::new(&path).exists() {
+            return Ok(Pak {
+                pak_path: path,
+                ..Pak::empty()
+            });
+        }
+        Pak::from_file(path).map_err(|e| match e.downcast::<PakError>() {
+            Ok(e) => *e,
+            Err(e) => match e.downcast::<io::Error>() {
+                Ok(e) => PakError::Io(*e),
+                Err(e) => PakError::Corrupt(e.to_string()),
+            },
+        })
+    }
+
+    /// Like `from_file`, but fails unless the header starts with one of
+    /// `magics`, for games that use their own magic in an otherwise standard
+    /// Pak. Like every loader, keeps the magic found for `save` to write back.
+    pub fn from_file_with_magics(path: String, magics: &[&[u8; 4]]) -> Result<Pak, Box<dyn Error>> {
+        let mut pak = Pak::from_file(path)?;
+        let found = pak.header.id.as_bytes();
+        match magics.iter().find(|&&magic| found == magic) {
+            Some(&&magic) => pak.magic = magic,
+            None => {
+                return Err(Box::new(PakError::Corrupt(format!(
+                    "unexpected magic '{}'",
+                    pak.header.id.escape_default()
+                ))))
+            }
+        }
+        Ok(pak)
+    }
+
+    /// The magic `save` writes: the one the Pak was loaded with, or `MAGIC`
+    /// for a Pak built from scratch.
+    pub fn magic(&self) -> &[u8; 4] {
+        &self.magic
+    }
+
+    /// Loads a Pak whose contents are known in advance. Each entry's CRC32 is
+    /// checked against `expected` as its data is copied, stopping at the first
+    /// mismatch. Entries missing from `expected` aren't checked.
+    pub fn from_file_verified(path: String, expected: &HashMap<String, u32>) -> Result<Pak, PakError> {
+        let bytes = std::fs::read(&path)?;
+        Pak::parse(path, Arc::new(bytes), Endian::Little, RecordLayout::STANDARD, |file| match expected.get(&file.name) {
+            Some(&crc) if crc != file.crc32() => Err(PakError::Corrupt(format!(
+                "{} has CRC32 {:08x}, expected {:08x}",
+                file.name,
+                file.crc32(),
+                crc
+            ))),
+            _ => Ok(()),
+        })
+    }
+
+    /// Parses a Pak held in memory without doing any IO. Malformed input of
+    /// any kind gives an error rather than a panic, which makes this the entry
+    /// point for fuzzing the parser. The data is copied once into a buffer the
+    /// entries share.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Pak, PakError> {
+        Pak::parse("".to_string(), Arc::new(bytes.to_vec()), Endian::Little, RecordLayout::STANDARD, |_| Ok(()))
+    }
+
+    /// Like `from_file`, for custom-engine Paks whose file table records
+    /// have a non-standard shape. The layout only affects loading; `save`
+    /// writes standard records.
+    pub fn from_file_with_layout(path: String, layout: RecordLayout) -> Result<Pak, PakError> {
+        let bytes = decompress(std::fs::read(&path)?)?;
+        Pak::parse(path, Arc::new(bytes), Endian::Little, layout, |_| Ok(()))
+    }
+
+    /// Like `from_bytes`, with the file table records in `layout`.
+    pub fn from_bytes_with_layout(bytes: &[u8], layout: RecordLayout) -> Result<Pak, PakError> {
+        Pak::parse("".to_string(), Arc::new(bytes.to_vec()), Endian::Little, layout, |_| Ok(()))
+    }
+
+    /// Best-effort recovery for a Pak whose header is damaged but whose file
+    /// table and data survived. Instead of trusting the header, every byte
+    /// position is tried as the start of a table record; a record counts if
+    /// its name is NUL-terminated, passes `sanitize_name`, and its data lies
+    /// past the header, inside `bytes` and clear of the record itself.
+    /// The longest run of such records back to back is taken as the table,
+    /// and the first record with a name wins. Entries whose records were
+    /// destroyed, or that sit past a damaged record, aren't recovered.
+    pub fn repair_scan(bytes: &[u8]) -> Pak {
+        let buf = Arc::new(bytes.to_vec());
+        let mut runs: Vec<Vec<PakFileEntry>> = Vec::new();
+        let mut run: Vec<PakFileEntry> = Vec::new();
+        let mut pos = HEADER_SIZE;
+        while pos + ENTRY_SIZE <= buf.len() {
+            match plausible_record(&buf, pos) {
+                Some(entry) => {
+                    run.push(entry);
+                    pos += ENTRY_SIZE;
+                }
+                None => {
+                    if !run.is_empty() {
+                        runs.push(std::mem::take(&mut run));
+                    }
+                    pos += 1;
+                }
+            }
+        }
+        runs.push(run);
+
+        // Stray matches inside entry data rarely line up; the table is the
+        // longest run of records.
+        let longest = runs.iter().map(Vec::len).max().unwrap_or(0);
+        let mut files = runs.into_iter().find(|run| run.len() == longest).unwrap_or_default();
+        let mut names: HashSet<String> = HashSet::new();
+        files.retain(|entry| names.insert(entry.name.clone()));
+        for entry in files.iter_mut() {
+            let range = entry.offset as usize..entry.offset as usize + entry.size as usize;
+            entry.data = EntryData::shared(&buf, range);
+        }
+
+        let mut pak = Pak::empty();
+        pak.files = files;
+        pak
+    }
+
+    /// Parses a whole Pak held in memory, calling `check` on every entry once
+    /// its data is in place. The entries' data point into `bytes`.
+    fn parse(
+        path: String,
+        bytes: Arc<Vec<u8>>,
+        endian: Endian,
+        layout: RecordLayout,
+        mut check: impl FnMut(&PakFileEntry) -> Result<(), PakError>,
+    ) -> Result<Pak, PakError> {
+        let pakheader = PakHeader::from_u8_endian(&bytes, endian)?;
+        let num_files = pakheader.size as usize / layout.stride;
+
+        check_table_fits(&pakheader, bytes.len() as u64, layout.stride).map_err(|e| match e {
+            PakError::Corrupt(msg) if endian == Endian::Little && Endian::detect(&bytes) == Some(Endian::Big) => {
+                PakError::Corrupt(format!("{}, the header looks big-endian", msg))
+            }
+            e => e,
+        })?;
 
-        let file_table_offset = pakheader.offset;
-        let mut my_offset: u32 = 0;
-        let mut pakfiles: Vec<PakFileEntry> = Vec::new();
+        let file_table_offset = pakheader.offset as usize;
+        let mut my_offset: usize = 0;
+        let mut pakfiles: Vec<PakFileEntry> = Vec::with_capacity(num_files);
 
         for _i in 0..num_files {
-            let file_entry = PakFileEntry::from_u8(
-                &(&bytes[(file_table_offset + my_offset) as usize
-                    ..(file_table_offset + my_offset + 64) as usize])
-                    .to_vec(),
-                &bytes,
+            let file_entry = layout.read_record(
+                &bytes[file_table_offset + my_offset..file_table_offset + my_offset + layout.stride],
+                endian,
             );
             pakfiles.push(file_entry);
 
-            my_offset += 64;
+            my_offset += layout.stride;
+        }
+
+        check_table_placement(&pakheader, &pakfiles)?;
+        for file in pakfiles.iter_mut() {
+            let range = file.offset as usize..file.offset as usize + file.size as usize;
+            if range.end > bytes.len() {
+                return Err(PakError::Corrupt(format!("data of {} runs past the end of the file", file.name)));
+            }
+            file.data = EntryData::shared(&bytes, range);
+            check(file)?;
         }
 
         Ok(Pak {
             pak_path: path,
+            table_layout: table_layout_of(&pakheader, &pakfiles),
             header: pakheader,
             files: pakfiles,
+            endian,
+            magic: bytes[..4].try_into().unwrap(),
+            ..Pak::empty()
         })
     }
 
-    #[allow(dead_code)]
-    #[no_mangle]
-    pub fn  add_file(&mut self, file: PakFileEntry) -> Result<&mut Pak, Box<dyn Error>> {
-        match self.files.iter().find(|f| f.name.eq(&file.name)) {
-            Some(_) => Err(Box::new(PakFileError {
-                msg: "File already exists".to_string(),
-            })),
-            None => {
-                self.files.push(file);
-                Ok(self)
+    /// Writes the `mtime` of every entry that has one to a sidecar file, one
+    /// `<unix seconds>\t<name>` line per entry. `from_file` picks up
+    /// `<pak>.times` automatically so extracted files get their times back.
+    pub fn write_times_sidecar(&self, path: String) -> Result<(), Box<dyn Error>> {
+        let mut f = BufWriter::new(File::create(path)?);
+        for file in self.files.iter() {
+            if let Some(mtime) = file.mtime {
+                writeln!(f, "{}\t{}", mtime, file.name)?;
             }
         }
+        f.flush()?;
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    #[no_mangle]
-    pub fn  remove_file(&mut self, filename: String) -> Result<(), Box<dyn Error>> {
-        if let Some(p) = self.files.iter().position(|p| p.name.eq(&filename)) {
-            self.files.remove(p);
-            Ok(())
-        } else {
-            Err(Box::new(PakFileError {
-                msg: "file entry not found".to_string(),
-            }))
+    /// Sets the `mtime` of the entries listed in a sidecar written by
+    /// `write_times_sidecar`. Names not in the Pak are ignored.
+    pub fn read_times_sidecar(&mut self, path: String) -> Result<(), Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        for line in contents.lines().filter(|l| !l.is_empty()) {
+            let parsed = line
+                .split_once('\t')
+                .and_then(|(mtime, name)| Some((mtime.parse::<i64>().ok()?, name)));
+            let (mtime, name) = parsed.ok_or_else(|| PakFileError {
+                msg: format!("Malformed times sidecar line: {}", line),
+            })?;
+            if let Some(file) = self.files.iter_mut().find(|f| f.name.eq(name)) {
+                file.mtime = Some(mtime);
+            }
         }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    #[no_mangle]
-    pub fn save(&self, filename: String) -> Result<(), Box<dyn Error>> {
-        let mut hdr = PakHeader::new();
-        hdr.offset = 12;
-        hdr.size = (self.files.len() * 64) as u32;
-        
-        let mut f = File::create(filename)?;
-        hdr.write_to(&f)?;
-
+    /// Writes the `mode` of every entry that has one to a sidecar file, one
+    /// `<octal mode>\t<name>` line per entry. Like `<pak>.times`, `from_file`
+    /// picks up `<pak>.modes` automatically, so on Unix extracted files get
+    /// their permissions back, e.g. the executable bit of a packed script.
+    pub fn write_modes_sidecar(&self, path: String) -> Result<(), Box<dyn Error>> {
+        let mut f = BufWriter::new(File::create(path)?);
         for file in self.files.iter() {
-            file.write_to(&f)?;
+            if let Some(mode) = file.mode {
+                writeln!(f, "{:o}\t{}", mode, file.name)?;
+            }
         }
+        f.flush()?;
+        Ok(())
+    }
 
-        for file in self.files.iter() {
-            f.seek(SeekFrom::Start(file.offset as u64))?;
-            io::Write::write(&mut f, file.data.as_slice())?;
+    /// Sets the `mode` of the entries listed in a sidecar written by
+    /// `write_modes_sidecar`. Names not in the Pak are ignored.
+    pub fn read_modes_sidecar(&mut self, path: String) -> Result<(), Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        for line in contents.lines().filter(|l| !l.is_empty()) {
+            let parsed = line
+                .split_once('\t')
+                .and_then(|(mode, name)| Some((u32::from_str_radix(mode, 8).ok()?, name)));
+            let (mode, name) = parsed.ok_or_else(|| PakFileError {
+                msg: format!("Malformed modes sidecar line: {}", line),
+            })?;
+            if let Some(file) = self.files.iter_mut().find(|f| f.name.eq(name)) {
+                file.mode = Some(mode & 0o777);
+            }
         }
-
         Ok(())
     }
 
-    pub fn append_file(&mut self, infilepath: String, pakfilepath: String) -> Result<(), Box<dyn Error>> {
-        let newfilepath = path::Path::new(&infilepath);
-        if ! newfilepath.exists() {
-            return Err(Box::new(PakFileError{ msg: "File does not exist!".to_string() }))
+    /// The header as 12 bytes in this Pak's byte order, from its current
+    /// fields. For a freshly loaded Pak this matches the header on disk.
+    pub fn raw_header_bytes(&self) -> [u8; HEADER_SIZE] {
+        self.header.as_bytes_endian(self.endian)
+    }
+
+    /// The file table as 64-byte records built from the entries' current
+    /// names, offsets and sizes, in table order. For a freshly loaded Pak this
+    /// matches the table on disk. Names are cut to the 56-byte field as is.
+    pub fn raw_table_bytes(&self) -> Vec<u8> {
+        let mut table: Vec<u8> = Vec::with_capacity(self.files.len() * ENTRY_SIZE);
+        for file in self.files.iter() {
+            let mut name = [0u8; NAME_SIZE];
+            let len = file.name.len().min(NAME_SIZE);
+            name[..len].copy_from_slice(&file.name.as_bytes()[..len]);
+            table.extend_from_slice(&name);
+            // Writing to a Vec can't fail.
+            self.endian.write_u32(&mut table, file.offset).unwrap();
+            self.endian.write_u32(&mut table, file.size).unwrap();
         }
+        table
+    }
 
+    /// Every entry's data keyed by name. If names repeat, the entry later in
+    /// the table wins.
+    pub fn into_map(self) -> HashMap<String, Vec<u8>> {
+        self.files
+            .into_iter()
+            .map(|f| {
+                let data = f.data().to_vec();
+                (f.name, data)
+            })
+            .collect()
+    }
 
-        fn get_last_offset(path: String) -> u32 {
-            let f = File::open(path).unwrap();
-            f.metadata().unwrap().len() as u32
+    /// Builds a Pak from a name to data map, with entries sorted by name so
+    /// the result doesn't depend on the map's iteration order. Offsets are
+    /// assigned on save.
+    pub fn from_map(map: HashMap<String, Vec<u8>>) -> Result<Pak, PakError> {
+        let mut files: Vec<PakFileEntry> = Vec::with_capacity(map.len());
+        for (name, data) in map {
+            if name.is_empty() || name.len() >= NAME_SIZE {
+                return Err(PakError::InvalidName(name));
+            }
+            files.push(PakFileEntry::new(name, 0, data));
         }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut pak = Pak::empty();
+        pak.files = files;
+        Ok(pak)
+    }
+
+    /// Looks up an entry by its exact name.
+    pub fn find(&self, name: &str) -> Option<&PakFileEntry> {
+        self.files.iter().find(|f| f.name.eq(name))
+    }
+
+    /// Like `find`, for editing the entry, e.g. through `PakFileEntry::data_mut`.
+    pub fn entry_mut(&mut self, name: &str) -> Option<&mut PakFileEntry> {
+        self.forget_digests();
+        self.files.iter_mut().find(|f| f.name.eq(name))
+    }
+
+    /// Drops the digests `find_by_sha256` keeps, for every method that
+    /// adds, removes or changes entries.
+    fn forget_digests(&mut self) {
+        self.sha256_index = HashIndexCache::default();
+    }
 
-        let last_offset = get_last_offset(self.pak_path.clone());
-        
-        fn get_file_data(path: String) -> Vec<u8> {
-            let mut f = File::open(path).unwrap();
-            let mut vec: Vec<u8> = Vec::new();
-            let buf: &mut Vec<u8> = vec.as_mut();
-            f.read_to_end(buf).unwrap();
-            buf.to_vec()
+    /// Every entry whose data has the SHA-256 `digest`, in table order, e.g.
+    /// all the names that share one piece of content. The digests are worked
+    /// out on the first call and kept until a method adds, removes or changes
+    /// entries. Changes made straight through `files` aren't noticed; use
+    /// `entry_mut` or the other methods for those.
+    pub fn find_by_sha256(&self, digest: &[u8; 32]) -> Vec<&PakFileEntry> {
+        let mut cache = self.sha256_index.0.lock().unwrap_or_else(|e| e.into_inner());
+        let index = cache.get_or_insert_with(|| {
+            let mut by_digest: HashMap<[u8; 32], Vec<usize>> = HashMap::new();
+            for (i, file) in self.files.iter().enumerate() {
+                by_digest.entry(Sha256::digest(file.data()).into()).or_default().push(i);
+            }
+            HashIndex { by_digest }
+        });
+        match index.by_digest.get(digest) {
+            Some(indices) => indices.iter().filter_map(|&i| self.files.get(i)).collect(),
+            None => Vec::new(),
         }
-        let data = get_file_data(infilepath);
-        
-        let fe = PakFileEntry::new(pakfilepath.to_string(), last_offset, data);
-        self.add_file(fe).unwrap();
-        Ok(())
     }
-}
 
-impl std::fmt::Display for Pak { 
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "<Pak structure from file {} with {} files>",
-            self.pak_path,
-            self.files.len()
-        )
+    /// Entries whose names match the glob `pattern`, in table order. As in a
+    /// shell, `*` stays within one directory and `**` crosses them.
+    pub fn matching(&self, pattern: &str) -> Result<impl Iterator<Item = &PakFileEntry>, PakError> {
+        let glob = compile_glob(pattern)?;
+        Ok(self.files.iter().filter(move |f| glob.is_match(&f.name)))
     }
-}
 
-#[derive(Debug, Clone)]
-#[repr(C)]
-pub struct PakFileError {
-    pub msg: String,
-}
+    /// The entry names in table order.
+    pub fn entry_names(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|f| f.name.as_str())
+    }
 
-impl std::fmt::Display for PakFileError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
+    /// The entries in the order their data sits in the file, by stored
+    /// offset, to compare a Pak's physical layout with how it gets read.
+    /// Entries at the same offset stay in table order.
+    pub fn entries_by_disk_order(&self) -> Vec<&PakFileEntry> {
+        let mut entries: Vec<&PakFileEntry> = self.files.iter().collect();
+        entries.sort_by_key(|f| f.offset);
+        entries
     }
-}
 
-impl Error for PakFileError {}
+    /// Calls `f` on every entry in table order.
+    pub fn walk(&self, f: impl FnMut(&PakFileEntry)) {
+        self.files.iter().for_each(f);
+    }
+
+    /// Like `walk`, but stops at the first error `f` returns and hands it back.
+    pub fn try_walk<E>(&self, f: impl FnMut(&PakFileEntry) -> Result<(), E>) -> Result<(), E> {
+        self.files.iter().try_for_each(f)
+    }
+
+    /// Byte range of an entry's data within the Pak file, as recorded in the
+    /// table, for callers doing their own IO on the file.
+    pub fn entry_data_range(&self, name: &str) -> Option<Range<u64>> {
+        self.find(name)
+            .map(|f| f.offset as u64..f.offset as u64 + f.size as u64)
+    }
+
+    /// Builds a Pak from the files of a zip archive, using the zip paths as
+    /// entry names. Directories are skipped, as are names that don't fit the
+    /// 56-byte, NUL-terminated name field (with a warning).
+    pub fn from_zip(path: String) -> Result<Pak, Box<dyn Error>> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let mut pak = Pak::empty();
+
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if file.is_dir() {
+                continue;
+            }
+            let name = file.name().to_string();
+            if name.len() >= NAME_SIZE {
+                log::warn!("Skipping '{}': name is longer than 55 bytes", name);
+                continue;
+            }
+
+            let mut data: Vec<u8> = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut data)?;
+            pak.add_file(PakFileEntry::new(name, 0, data))?;
+        }
+
+        Ok(pak)
+    }
+
+    /// Parses a Pak from a stream, starting at the reader's current position.
+    /// Offsets in the Pak are taken relative to that position, so a Pak embedded
+    /// inside a larger file can be read directly.
+    pub fn from_reader<R: Read + Seek>(mut reader: R) -> Result<Pak, Box<dyn Error>> {
+        fn read_at<R: Read + Seek>(reader: &mut R, pos: u64, len: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+            reader.seek(SeekFrom::Start(pos))?;
+            let mut buf: Vec<u8> = Vec::new();
+            reader.take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() != len as usize {
+                return Err(Box::new(PakFileError {
+                    msg: "unexpected end of Pak data".to_string(),
+                }));
+            }
+            Ok(buf)
+        }
+
+        let base = reader.stream_position()?;
+        let header_buf = read_at(&mut reader, base, HEADER_SIZE as u32)?;
+        let header = PakHeader::from_u8(&header_buf)?;
+        let len = reader.seek(SeekFrom::End(0))? - base;
+        check_table_fits(&header, len, ENTRY_SIZE)?;
+        let table = read_at(&mut reader, base + header.offset as u64, header.size)?;
+
+        let mut files: Vec<PakFileEntry> = table
+            .chunks_exact(ENTRY_SIZE)
+            .map(|record| PakFileEntry::from_record(record, Endian::Little))
+            .collect();
+        check_table_placement(&header, &files)?;
+        for entry in files.iter_mut() {
+            entry.data = EntryData::owned(read_at(&mut reader, base + entry.offset as u64, entry.size)?);
+        }
+
+        Ok(Pak {
+            table_layout: table_layout_of(&header, &files),
+            header,
+            files,
+            magic: header_buf[..4].try_into().unwrap(),
+            ..Pak::empty()
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn  add_file(&mut self, mut file: PakFileEntry) -> Result<&mut Pak, Box<dyn Error>> {
+        self.forget_digests();
+        file.name = self.checked_name(&file.name)?;
+        match self.files.iter().find(|f| f.name.eq(&file.name)) {
+            Some(_) if !self.allow_duplicates => Err(Box::new(PakFileError {
+                msg: "File already exists".to_string(),
+            })),
+            _ => {
+                self.files.push(file);
+                Ok(self)
+            }
+        }
+    }
+
+    /// The name a new or renamed entry ends up with: `sanitize_name`'s, also
+    /// checked against `ascii_names_only`.
+    fn checked_name(&self, name: &str) -> Result<String, PakError> {
+        let name = Pak::sanitize_name(name)?;
+        if self.ascii_names_only && !is_printable_ascii(&name) {
+            return Err(PakError::InvalidName(name));
+        }
+        Ok(name)
+    }
+
+    /// Turns an entry name into a relative path to extract it to, or `None`
+    /// if it is absolute or has `..` components, backslashes or a drive
+    /// prefix that could escape the target directory. Unlike `sanitize_name`
+    /// it doesn't limit the length, since names read from a Pak can fill
+    /// their whole field.
+    pub fn relative_path(name: &str) -> Option<path::PathBuf> {
+        if name.starts_with('/') || name.contains('\\') {
+            return None;
+        }
+        let components: Vec<&str> = name.split('/').filter(|c| !c.is_empty() && *c != ".").collect();
+        if components.is_empty() || components.iter().any(|c| *c == ".." || c.contains(':')) {
+            return None;
+        }
+        Some(components.iter().collect())
+    }
+
+    /// Cleans up an entry name: `\` separators become `/`, and empty and `.`
+    /// components are dropped. Names that are absolute, contain `..` or a
+    /// drive letter, are empty, or don't fit the 56-byte NUL-terminated name
+    /// field are rejected. A trailing `/` is kept for directory markers.
+    pub fn sanitize_name(name: &str) -> Result<String, PakError> {
+        let invalid = || PakError::InvalidName(name.to_string());
+        if name.starts_with(['/', '\\']) {
+            return Err(invalid());
+        }
+
+        let mut components: Vec<&str> = Vec::new();
+        for component in name.split(['/', '\\']) {
+            match component {
+                "" | "." => {}
+                ".." => return Err(invalid()),
+                c if c.contains(':') => return Err(invalid()),
+                c => components.push(c),
+            }
+        }
+        if components.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut clean = components.join("/");
+        if name.ends_with(['/', '\\']) {
+            clean.push('/');
+        }
+        if clean.len() >= NAME_SIZE {
+            return Err(invalid());
+        }
+        Ok(clean)
+    }
+
+    /// Adds a zero-byte entry, e.g. a marker file some games look for.
+    pub fn add_empty(&mut self, name: String) -> Result<&mut Pak, Box<dyn Error>> {
+        self.add_file(PakFileEntry::new(name, 0, Vec::new()))
+    }
+
+    /// Inserts `file` at table position `index`, shifting later entries back.
+    /// Only the table order is affected; offsets are recomputed on save.
+    pub fn insert_file_at(&mut self, index: usize, mut file: PakFileEntry) -> Result<(), PakError> {
+        self.forget_digests();
+        if index > self.files.len() {
+            return Err(PakError::IndexOutOfRange(index));
+        }
+        file.name = self.checked_name(&file.name)?;
+        if !self.allow_duplicates && self.find(&file.name).is_some() {
+            return Err(PakError::Duplicate(file.name));
+        }
+        self.files.insert(index, file);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn  remove_file(&mut self, filename: String) -> Result<(), Box<dyn Error>> {
+        match self.take_file(&filename) {
+            Some(_) => Ok(()),
+            None => Err(Box::new(PakFileError {
+                msg: format!("file entry not found: {}", filename),
+            })),
+        }
+    }
+
+    /// Adds every file below `dir`, named by its path relative to `base`
+    /// (see `PakFileEntry::name_relative_to`). Files are added in sorted path
+    /// order; nothing is added if any name is taken or invalid. Returns how
+    /// many files were added.
+    pub fn append_dir<P: AsRef<path::Path>, B: AsRef<path::Path>>(&mut self, dir: P, base: B) -> Result<usize, PakError> {
+        self.forget_digests();
+        fn walk(dir: &path::Path, found: &mut Vec<path::PathBuf>) -> io::Result<()> {
+            let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+            entries.sort_by_key(|e| e.path());
+            for entry in entries {
+                if entry.file_type()?.is_dir() {
+                    walk(&entry.path(), found)?;
+                } else {
+                    found.push(entry.path());
+                }
+            }
+            Ok(())
+        }
+
+        let mut found: Vec<path::PathBuf> = Vec::new();
+        walk(dir.as_ref(), &mut found)?;
+
+        let mut added: Vec<PakFileEntry> = Vec::with_capacity(found.len());
+        for path in found {
+            let name = self.checked_name(&PakFileEntry::name_relative_to(&path, base.as_ref())?)?;
+            if !self.allow_duplicates && (self.find(&name).is_some() || added.iter().any(|f| f.name == name)) {
+                return Err(PakError::Duplicate(name));
+            }
+            added.push(PakFileEntry::from_path(name, &path)?);
+        }
+
+        let count = added.len();
+        self.files.extend(added);
+        Ok(count)
+    }
+
+    /// Swaps the data of the entry called `name` for `data`, keeping its
+    /// place in the table.
+    pub fn replace_file(&mut self, name: &str, data: Vec<u8>) -> Result<(), PakError> {
+        self.forget_digests();
+        let file = self
+            .files
+            .iter_mut()
+            .find(|f| f.name.eq(name))
+            .ok_or_else(|| PakError::NotFound(name.to_string()))?;
+        file.size = data.len() as u32;
+        file.data = EntryData::owned(data);
+        Ok(())
+    }
+
+    /// Like `replace_file` with the contents and modification time of the
+    /// file at `source`.
+    pub fn replace_file_from_path<P: AsRef<path::Path>>(&mut self, name: &str, source: P) -> Result<(), PakError> {
+        self.forget_digests();
+        let file = self
+            .files
+            .iter_mut()
+            .find(|f| f.name.eq(name))
+            .ok_or_else(|| PakError::NotFound(name.to_string()))?;
+        let entry = PakFileEntry::from_path(name.to_string(), source)?;
+        file.size = entry.size;
+        file.mtime = entry.mtime;
+        file.mode = entry.mode;
+        file.data = entry.data;
+        Ok(())
+    }
+
+    /// Keeps only the entries `pred` returns true for, in one pass.
+    pub fn retain(&mut self, pred: impl FnMut(&PakFileEntry) -> bool) {
+        self.forget_digests();
+        self.files.retain(pred);
+    }
+
+    /// Removes the entry called `name` and hands it back, data included, e.g.
+    /// to move it into another Pak.
+    pub fn take_file(&mut self, name: &str) -> Option<PakFileEntry> {
+        self.forget_digests();
+        let p = self.files.iter().position(|f| f.name.eq(name))?;
+        Some(self.files.remove(p))
+    }
+
+    #[allow(dead_code)]
+    pub fn save(&self, filename: String) -> Result<(), Box<dyn Error>> {
+        self.save_with(filename, &self.save_options())
+    }
+
+    /// Like `save`, but pads the data so every entry starts at a multiple of
+    /// `alignment` bytes. The result is still a plain Pak.
+    pub fn save_aligned(&self, filename: String, alignment: u32) -> Result<(), Box<dyn Error>> {
+        self.save_with(
+            filename,
+            &SaveOptions {
+                alignment,
+                ..self.save_options()
+            },
+        )
+    }
+
+    /// Like `save`, but byte-identical entries share a single data region
+    /// instead of being stored once per name.
+    pub fn save_deduplicated(&self, filename: String) -> Result<(), Box<dyn Error>> {
+        self.save_with(
+            filename,
+            &SaveOptions {
+                deduplicate: true,
+                ..self.save_options()
+            },
+        )
+    }
+
+    /// Like `save`, but writes the data of the entries named in `data_order`
+    /// first and in that order, e.g. the order an engine loads them in. The
+    /// table keeps the order of `files`. Unlisted entries follow in table order.
+    pub fn save_ordered(&self, filename: String, data_order: &[&str]) -> Result<(), Box<dyn Error>> {
+        let data_order = data_order
+            .iter()
+            .map(|name| {
+                self.files.iter().position(|f| f.name.eq(name)).ok_or_else(|| PakFileError {
+                    msg: format!("file entry not found: {}", name),
+                })
+            })
+            .collect::<Result<Vec<usize>, PakFileError>>()?;
+        self.save_with(
+            filename,
+            &SaveOptions {
+                data_order,
+                ..self.save_options()
+            },
+        )
+    }
+
+    /// Like `save`, with the file table placed according to `layout`.
+    pub fn save_with_layout(&self, filename: String, layout: TableLayout) -> Result<(), Box<dyn Error>> {
+        self.save_with(
+            filename,
+            &SaveOptions {
+                table_layout: layout,
+                ..SaveOptions::default()
+            },
+        )
+    }
+
+    fn save_with(&self, filename: String, options: &SaveOptions) -> Result<(), Box<dyn Error>> {
+        // Check before `File::create` truncates whatever is already there.
+        self.check_max_size(options)?;
+        let f = File::create(filename)?;
+        self.write_with(BufWriter::new(f), options)
+    }
+
+    /// Saves over `filename` by writing a temp file next to it and renaming it
+    /// into place, so a crash mid-write never leaves a truncated Pak behind.
+    /// Use this when `filename` is also the Pak being rewritten.
+    pub fn save_in_place(&self, filename: String) -> Result<(), Box<dyn Error>> {
+        self.save_via_temp(filename, false)
+    }
+
+    /// Like `save_in_place`, but also flushes the temp file to disk before
+    /// renaming it and then syncs the directory, so after a power loss
+    /// `filename` holds either the old Pak or the complete new one.
+    pub fn save_atomic(&self, filename: String) -> Result<(), Box<dyn Error>> {
+        self.save_via_temp(filename, true)
+    }
+
+    fn save_via_temp(&self, filename: String, durable: bool) -> Result<(), Box<dyn Error>> {
+        let target = path::Path::new(&filename);
+        let dir = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => path::Path::new("."),
+        };
+
+        // Loading unpacks compressed Paks but saving doesn't pack them again,
+        // so replacing one would leave a plain Pak under the compressed name.
+        let mut magic = [0u8; 4];
+        if let Ok(n) = File::open(target).and_then(|mut f| f.read(&mut magic)) {
+            if is_compressed(&magic[..n]) {
+                return Err(Box::new(PakFileError {
+                    msg: format!("'{}' is compressed, save to a new file and compress that instead", filename),
+                }));
+            }
+        }
+
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        self.write_to(BufWriter::new(tmp.as_file_mut()))?;
+        // The temp file starts out private; a rewritten Pak keeps its own permissions.
+        if let Ok(metadata) = fs::metadata(target) {
+            tmp.as_file().set_permissions(metadata.permissions())?;
+        }
+        if durable {
+            tmp.as_file().sync_all()?;
+        }
+        tmp.persist(target)?;
+        // Makes the rename itself durable. Windows can't open directories
+        // like this and doesn't need it.
+        #[cfg(unix)]
+        if durable {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Writes every entry into a new zip archive at `path`, using the entry
+    /// names as zip paths. Each directory prefix gets its own zip directory entry.
+    pub fn export_zip(&self, path: String) -> Result<(), Box<dyn Error>> {
+        let mut zip = ZipWriter::new(File::create(path)?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        let mut dirs: HashSet<&str> = HashSet::new();
+        for file in self.files.iter() {
+            for (i, _) in file.name.match_indices('/') {
+                let dir = &file.name[..=i];
+                if dirs.insert(dir) {
+                    zip.add_directory(dir, options)?;
+                }
+            }
+            // Directory markers were just added as directories above.
+            if file.name.ends_with('/') {
+                continue;
+            }
+            zip.start_file(file.name.as_str(), options)?;
+            zip.write_all(file.data())?;
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Serializes the whole Pak: header, file table, then the data.
+    pub fn write_to<W: io::Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        self.write_with(writer, &self.save_options())
+    }
+
+    /// Makes `save`, `write_to` and the other `save_*` variants put the file
+    /// table after all data and point the header at it, the way id's own
+    /// tools laid Paks out. Some third-party extractors only cope with that.
+    /// Loaded Paks start out with the placement they were read with.
+    /// `save_with_layout` still uses the layout it's given.
+    pub fn set_file_table_offset_last(&mut self, last: bool) {
+        self.table_layout = if last { TableLayout::Last } else { TableLayout::First };
+    }
+
+    /// Options the plain `save` uses, with this Pak's table placement.
+    fn save_options(&self) -> SaveOptions {
+        SaveOptions {
+            table_layout: self.table_layout,
+            ..SaveOptions::default()
+        }
+    }
+
+    /// Table indices in the order `write_with` writes their data.
+    fn data_order(&self, options: &SaveOptions) -> Vec<usize> {
+        let mut listed = vec![false; self.files.len()];
+        let mut order: Vec<usize> = Vec::with_capacity(self.files.len());
+        for i in options.data_order.iter().copied().chain(0..self.files.len()) {
+            if !listed[i] {
+                listed[i] = true;
+                order.push(i);
+            }
+        }
+        order
+    }
+
+    /// Where each entry's data goes when written with `options`, by table
+    /// index. Data is laid out right after the file table in `data_order`, so
+    /// the stored offsets are never trusted. Deduplicated entries point back
+    /// at an earlier region.
+    fn plan_offsets(&self, options: &SaveOptions) -> Vec<u32> {
+        let alignment = options.alignment.max(1);
+        let mut offset = match options.table_layout {
+            TableLayout::First => (HEADER_SIZE + self.files.len() * ENTRY_SIZE) as u32,
+            TableLayout::Last => HEADER_SIZE as u32,
+        };
+        let mut offsets: Vec<u32> = vec![0; self.files.len()];
+        let mut seen: HashMap<[u8; 32], Vec<usize>> = HashMap::new();
+
+        for i in self.data_order(options) {
+            let file = &self.files[i];
+            if options.deduplicate {
+                let candidates = seen.entry(Sha256::digest(file.data()).into()).or_default();
+                if let Some(&first) = candidates.iter().find(|&&j| self.files[j].data() == file.data()) {
+                    offsets[i] = offsets[first];
+                    continue;
+                }
+                candidates.push(i);
+            }
+
+            offset = offset.div_ceil(alignment) * alignment;
+            offsets[i] = offset;
+            offset += file.data().len() as u32;
+        }
+        offsets
+    }
+
+    /// Where the file table goes for `offsets` planned with `options`.
+    fn plan_table_offset(&self, options: &SaveOptions, offsets: &[u32]) -> u32 {
+        match options.table_layout {
+            TableLayout::First => HEADER_SIZE as u32,
+            TableLayout::Last => offsets
+                .iter()
+                .zip(self.files.iter())
+                .map(|(offset, file)| offset + file.data().len() as u32)
+                .fold(HEADER_SIZE as u32, u32::max),
+        }
+    }
+
+    /// Size in bytes of the file `save` (or `save_aligned` with `alignment`)
+    /// would write, without writing anything.
+    pub fn estimated_save_size(&self, alignment: Option<u32>) -> u64 {
+        self.planned_size(&SaveOptions {
+            alignment: alignment.unwrap_or(1),
+            ..self.save_options()
+        })
+    }
+
+    /// `(name, offset, size)` of every entry in table order, with the offsets
+    /// a plain `save` would give them rather than the stored ones, which go
+    /// stale once entries are added, removed or resized.
+    pub fn planned_layout(&self) -> impl Iterator<Item = (&str, u64, u64)> {
+        let offsets = self.plan_offsets(&self.save_options());
+        self.files
+            .iter()
+            .zip(offsets)
+            .map(|(file, offset)| (file.name.as_str(), offset as u64, file.data().len() as u64))
+    }
+
+    /// Size in bytes of the file `write_with` would produce.
+    fn planned_size(&self, options: &SaveOptions) -> u64 {
+        let offsets = self.plan_offsets(options);
+        let table_end = self.plan_table_offset(options, &offsets) as u64 + (self.files.len() * ENTRY_SIZE) as u64;
+        let content_end = offsets
+            .iter()
+            .zip(self.files.iter())
+            .map(|(offset, file)| *offset as u64 + file.data().len() as u64)
+            .fold(table_end, u64::max);
+        content_end + self.trailer.len() as u64
+    }
+
+    fn check_max_size(&self, options: &SaveOptions) -> Result<(), Box<dyn Error>> {
+        let size = self.planned_size(options);
+        match self.max_size {
+            Some(max_size) if size > max_size => Err(Box::new(PakFileError {
+                msg: format!(
+                    "Pak would be {} bytes, {} bytes over the limit of {}",
+                    size,
+                    size - max_size,
+                    max_size
+                ),
+            })),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_with<W: io::Write>(&self, mut writer: W, options: &SaveOptions) -> Result<(), Box<dyn Error>> {
+        self.check_max_size(options)?;
+
+        let offsets = self.plan_offsets(options);
+        let mut hdr = PakHeader::new();
+        hdr.id = String::from_utf8_lossy(&self.magic).into_owned();
+        hdr.offset = self.plan_table_offset(options, &offsets);
+        hdr.size = (self.files.len() * ENTRY_SIZE) as u32;
+        hdr.write_endian_to(&mut writer, self.endian)?;
+
+        // Build the table up front so a bad name fails before any data is written.
+        let mut table: Vec<u8> = Vec::with_capacity(hdr.size as usize);
+        for (file, offset) in self.files.iter().zip(offsets.iter()) {
+            file.write_record_to(&mut table, *offset, file.data().len() as u32, self.endian)?;
+        }
+
+        let mut position = HEADER_SIZE as u32;
+        if options.table_layout == TableLayout::First {
+            writer.write_all(&table)?;
+            position += hdr.size;
+        }
+        for i in self.data_order(options) {
+            let (file, offset) = (&self.files[i], offsets[i]);
+            if offset < position {
+                // Shares the data of an entry that was already written.
+                continue;
+            }
+            writer.write_all(&vec![0; (offset - position) as usize])?;
+            file.write_data(&mut writer)?;
+            position = offset + file.data().len() as u32;
+        }
+        if options.table_layout == TableLayout::Last {
+            writer.write_all(&table)?;
+        }
+        writer.write_all(&self.trailer)?;
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Sets an opaque blob, e.g. build info, to write after all entry data on
+    /// save. Engines never read past the data the table points at, so the Pak
+    /// stays loadable. Loading a Pak doesn't keep its trailer; use
+    /// `read_trailer` for that.
+    pub fn set_trailer(&mut self, bytes: Vec<u8>) {
+        self.trailer = bytes;
+    }
+
+    /// The blob set with `set_trailer`.
+    pub fn trailer(&self) -> &[u8] {
+        &self.trailer
+    }
+
+    /// Reads the bytes between the end of the last header, table or entry
+    /// data and the end of the Pak file at `path`.
+    pub fn read_trailer(path: String) -> Result<Vec<u8>, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let pak = Pak::from_bytes(&bytes)?;
+        let table_end = pak.header.offset as u64 + pak.header.size as u64;
+        let content_end = pak
+            .files
+            .iter()
+            .map(|f| f.offset as u64 + f.size as u64)
+            .fold(table_end.max(HEADER_SIZE as u64), u64::max);
+        Ok(bytes[(content_end as usize).min(bytes.len())..].to_vec())
+    }
+
+    /// Human readable report of the header fields, for poking at unknown Paks.
+    pub fn summary(&self) -> String {
+        let data_bytes: u64 = self.files.iter().map(|f| f.size as u64).sum();
+        format!(
+            "Magic: {}\nTable offset: {}\nTable size: {}\nEntries: {}\nData bytes: {}\nVariant: {}",
+            self.header.id,
+            self.header.offset,
+            self.header.size,
+            self.files.len(),
+            data_bytes,
+            self.header.variant()
+        )
+    }
+
+    /// Names with bytes outside printable ASCII (0x20 to 0x7E), in table order.
+    pub fn non_ascii_names(&self) -> Vec<&str> {
+        self.files
+            .iter()
+            .filter(|f| !is_printable_ascii(&f.name))
+            .map(|f| f.name.as_str())
+            .collect()
+    }
+
+    /// Names whose byte length is at least `threshold`, with that length,
+    /// to catch names getting close to the 55-byte limit.
+    pub fn name_length_warnings(&self, threshold: usize) -> Vec<(&str, usize)> {
+        self.files
+            .iter()
+            .filter(|f| f.name.len() >= threshold)
+            .map(|f| (f.name.as_str(), f.name.len()))
+            .collect()
+    }
+
+    /// Entry counts and sizes. An empty Pak gives all zeroes.
+    pub fn size_stats(&self) -> SizeStats {
+        let mut sizes: Vec<u64> = self.files.iter().map(|f| f.size as u64).collect();
+        sizes.sort_unstable();
+        let total_bytes: u64 = sizes.iter().sum();
+        let median = match sizes.len() {
+            0 => 0.0,
+            n if n % 2 == 1 => sizes[n / 2] as f64,
+            n => (sizes[n / 2 - 1] + sizes[n / 2]) as f64 / 2.0,
+        };
+
+        let mut histogram = [0; 4];
+        for &size in sizes.iter() {
+            let bucket = SizeStats::BUCKET_LIMITS.iter().take_while(|&&limit| size >= limit).count();
+            histogram[bucket] += 1;
+        }
+
+        SizeStats {
+            files: sizes.len(),
+            total_bytes,
+            min: sizes.first().copied().unwrap_or(0),
+            max: sizes.last().copied().unwrap_or(0),
+            mean: if sizes.is_empty() { 0.0 } else { total_bytes as f64 / sizes.len() as f64 },
+            median,
+            histogram,
+        }
+    }
+
+    /// Buckets the entries by extension. Files without one end up under `""`.
+    pub fn group_by_extension(&self) -> HashMap<String, Vec<&PakFileEntry>> {
+        let mut groups: HashMap<String, Vec<&PakFileEntry>> = HashMap::new();
+        for file in self.files.iter() {
+            groups
+                .entry(file.extension().unwrap_or("").to_string())
+                .or_default()
+                .push(file);
+        }
+        groups
+    }
+
+    /// SHA-256 over the logical contents: every entry's name and data, in name
+    /// order. Table order, offsets and gaps don't affect the result. Lengths
+    /// are hashed as little-endian u64 ahead of the bytes they describe.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let mut files: Vec<&PakFileEntry> = self.files.iter().collect();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut hasher = Sha256::new();
+        for file in files {
+            hasher.update((file.name.len() as u64).to_le_bytes());
+            hasher.update(file.name.as_bytes());
+            hasher.update((file.data.len() as u64).to_le_bytes());
+            hasher.update(file.data());
+        }
+        hasher.finalize().into()
+    }
+
+    /// Hex digest of every entry's data keyed by name, sorted by name so the
+    /// result can be diffed or checked in. If names repeat, the entry later
+    /// in the table wins.
+    pub fn checksum_manifest(&self, algo: HashAlgo) -> BTreeMap<String, String> {
+        self.files
+            .iter()
+            .map(|f| {
+                let digest = match algo {
+                    HashAlgo::Crc32 => format!("{:08x}", f.crc32()),
+                    HashAlgo::Sha1 => hex(&Sha1::digest(f.data())),
+                    HashAlgo::Sha256 => hex(&Sha256::digest(f.data())),
+                };
+                (f.name.clone(), digest)
+            })
+            .collect()
+    }
+
+    /// Whether both Paks hold the same names with the same data, ignoring
+    /// table order, offsets and gaps. Not a `PartialEq` impl since two such
+    /// Paks can still differ byte for byte on disk.
+    pub fn logically_eq(&self, other: &Pak) -> bool {
+        if self.files.len() != other.files.len() {
+            return false;
+        }
+        let mut ours: Vec<&PakFileEntry> = self.files.iter().collect();
+        let mut theirs: Vec<&PakFileEntry> = other.files.iter().collect();
+        ours.sort_by(|a, b| a.name.cmp(&b.name));
+        theirs.sort_by(|a, b| a.name.cmp(&b.name));
+        ours.iter()
+            .zip(theirs.iter())
+            .all(|(a, b)| a.name == b.name && a.data() == b.data())
+    }
+
+    /// What it takes to turn this Pak into `newer`, by entry name: entries
+    /// only `newer` has, entries whose data differs (with all of their new
+    /// data), and entries `newer` doesn't have.
+    pub fn diff_to_patch(&self, newer: &Pak) -> PakPatch {
+        let mut patch = PakPatch::default();
+        for file in self.files.iter() {
+            match newer.find(&file.name) {
+                None => patch.removed.push(file.name.clone()),
+                Some(new) if new.data() != file.data() => patch.changed.push((file.name.clone(), new.data().to_vec())),
+                Some(_) => {}
+            }
+        }
+        for file in newer.files.iter().filter(|f| self.find(&f.name).is_none()) {
+            patch.added.push((file.name.clone(), file.data().to_vec()));
+        }
+        patch
+    }
+
+    /// Applies a patch from `diff_to_patch`: removes, replaces, then appends
+    /// entries. Fails without changing anything if a removed or changed entry
+    /// is missing or an added one already exists.
+    pub fn apply_patch(&mut self, patch: &PakPatch) -> Result<(), PakError> {
+        self.forget_digests();
+        let mut pak = self.clone();
+        for name in patch.removed.iter() {
+            pak.take_file(name).ok_or_else(|| PakError::NotFound(name.clone()))?;
+        }
+        for (name, data) in patch.changed.iter() {
+            pak.replace_file(name, data.clone())?;
+        }
+        for (name, data) in patch.added.iter() {
+            if pak.checked_name(name)? != *name {
+                return Err(PakError::InvalidName(name.clone()));
+            }
+            if pak.find(name).is_some() {
+                return Err(PakError::Duplicate(name.clone()));
+            }
+            pak.files.push(PakFileEntry::new(name.clone(), 0, data.clone()));
+        }
+        *self = pak;
+        Ok(())
+    }
+
+    /// Assigns every entry the offset `save` would give it and updates the
+    /// header to match, so the in-memory Pak describes a contiguous layout.
+    pub fn compact(&mut self) {
+        let options = self.save_options();
+        let offsets = self.plan_offsets(&options);
+        self.header.offset = self.plan_table_offset(&options, &offsets);
+        for (file, offset) in self.files.iter_mut().zip(offsets) {
+            file.offset = offset;
+        }
+        self.header.size = (self.files.len() * ENTRY_SIZE) as u32;
+    }
+
+    /// Checks that the Pak is consistent: names are valid and unique, sizes
+    /// match the data, and the header, file table and data regions don't
+    /// overlap. Entries sharing the exact same region are allowed.
+    pub fn verify(&self) -> Result<(), PakError> {
+        let mut names: HashSet<&str> = HashSet::new();
+        for file in self.files.iter() {
+            if file.name.is_empty() || file.name.len() >= NAME_SIZE {
+                return Err(PakError::InvalidName(file.name.clone()));
+            }
+            if !names.insert(&file.name) {
+                return Err(PakError::Duplicate(file.name.clone()));
+            }
+            if file.size as usize != file.data.len() {
+                return Err(PakError::Corrupt(format!(
+                    "{} claims {} bytes but holds {}",
+                    file.name,
+                    file.size,
+                    file.data.len()
+                )));
+            }
+        }
+
+        check_table_placement(&self.header, &self.files)?;
+        if self.header.size as usize != self.files.len() * ENTRY_SIZE {
+            return Err(PakError::Corrupt(format!(
+                "file table is {} bytes, expected {}",
+                self.header.size,
+                self.files.len() * ENTRY_SIZE
+            )));
+        }
+
+        let mut regions: Vec<(Range<u64>, &str)> = self
+            .files
+            .iter()
+            .filter(|f| f.size > 0)
+            .map(|f| (f.offset as u64..f.offset as u64 + f.size as u64, f.name.as_str()))
+            .collect();
+        regions.sort_by_key(|(r, _)| (r.start, r.end));
+
+        let mut previous: Option<&(Range<u64>, &str)> = None;
+        for region in regions.iter() {
+            let (range, name) = region;
+            if range.start < HEADER_SIZE as u64 {
+                return Err(PakError::Corrupt(format!("{} overlaps the header", name)));
+            }
+            if let Some((prev, prev_name)) = previous {
+                if prev != range && range.start < prev.end {
+                    return Err(PakError::Corrupt(format!("{} overlaps {}", name, prev_name)));
+                }
+            }
+            previous = Some(region);
+        }
+        Ok(())
+    }
+
+    /// Byte ranges not covered by the header, the file table or any entry's
+    /// data, up to the end of the last region.
+    pub fn gaps(&self) -> Vec<Range<u64>> {
+        let mut regions: Vec<Range<u64>> = vec![
+            0..HEADER_SIZE as u64,
+            self.header.offset as u64..self.header.offset as u64 + self.header.size as u64,
+        ];
+        regions.extend(
+            self.files
+                .iter()
+                .map(|f| f.offset as u64..f.offset as u64 + f.size as u64),
+        );
+        regions.retain(|r| !r.is_empty());
+        regions.sort_by_key(|r| r.start);
+
+        let mut gaps: Vec<Range<u64>> = Vec::new();
+        let mut covered = 0;
+        for region in regions {
+            if region.start > covered {
+                gaps.push(covered..region.start);
+            }
+            covered = covered.max(region.end);
+        }
+        gaps
+    }
+
+    /// Every region of the Pak as described by the header and table: the
+    /// header, the file table, each entry's data and the gaps between them,
+    /// sorted by start offset. Empty regions are left out.
+    pub fn layout(&self) -> Vec<LayoutRegion> {
+        let table = self.header.offset as u64..self.header.offset as u64 + self.header.size as u64;
+        let mut regions: Vec<LayoutRegion> = vec![
+            LayoutRegion {
+                kind: RegionKind::Header,
+                start: 0,
+                end: HEADER_SIZE as u64,
+                name: None,
+            },
+            LayoutRegion {
+                kind: RegionKind::Table,
+                start: table.start,
+                end: table.end,
+                name: None,
+            },
+        ];
+        regions.extend(self.files.iter().map(|f| LayoutRegion {
+            kind: RegionKind::Data,
+            start: f.offset as u64,
+            end: f.offset as u64 + f.size as u64,
+            name: Some(f.name.clone()),
+        }));
+        regions.extend(self.gaps().into_iter().map(|gap| LayoutRegion {
+            kind: RegionKind::Gap,
+            start: gap.start,
+            end: gap.end,
+            name: None,
+        }));
+        regions.retain(|r| r.start < r.end);
+        regions.sort_by_key(|r| (r.start, r.end));
+        regions
+    }
+
+    /// Guesses the alignment the Pak was written with, to pass to
+    /// `save_aligned` so a re-save keeps the same offsets. This is the largest
+    /// power of two up to 4096 dividing every data offset, and is only
+    /// reported when the layout has padding gaps; without gaps the offsets
+    /// lining up is as likely to be chance.
+    pub fn detected_alignment(&self) -> Option<u32> {
+        if self.gaps().is_empty() {
+            return None;
+        }
+        let offsets = self.files.iter().filter(|f| f.size > 0).map(|f| f.offset);
+        let alignment = offsets
+            .map(|offset| if offset == 0 { 4096 } else { 1 << offset.trailing_zeros().min(12) })
+            .min()?;
+        if alignment > 1 {
+            Some(alignment)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a canonical copy of this Pak: entries sorted by name and laid
+    /// out back-to-back, with no gaps, on whichever side of the file table
+    /// this Pak keeps it. Fails if the result doesn't pass `verify`, e.g.
+    /// because of duplicate or bad names.
+    pub fn rebuild(&self) -> Result<Pak, PakError> {
+        let mut pak = self.empty_like();
+        pak.max_size = self.max_size;
+        pak.files = self
+            .files
+            .iter()
+            .map(|f| PakFileEntry {
+                offset: 0,
+                size: f.data.len() as u32,
+                mtime: None,
+                mode: None,
+                ..f.clone()
+            })
+            .collect();
+        pak.files.sort_by(|a, b| a.name.cmp(&b.name));
+        pak.compact();
+        pak.verify()?;
+        Ok(pak)
+    }
+
+    /// A new Pak holding copies of the entries matching `pred`, in table order.
+    pub fn filter(&self, pred: impl Fn(&PakFileEntry) -> bool) -> Pak {
+        let mut pak = self.empty_like();
+        pak.files = self.files.iter().filter(|f| pred(f)).cloned().collect();
+        pak
+    }
+
+    /// An empty Pak that saves like this one: same byte order, magic and
+    /// file table placement.
+    fn empty_like(&self) -> Pak {
+        Pak {
+            endian: self.endian,
+            magic: self.magic,
+            table_layout: self.table_layout,
+            ..Pak::empty()
+        }
+    }
+
+    /// Extracts every entry matching `pred` below `dir`, recreating the
+    /// directories in their names. Names that would land outside `dir` are
+    /// rejected before anything is written. Directory markers become empty
+    /// directories. Returns how many entries were extracted.
+    pub fn extract_to_dir<P: AsRef<path::Path>>(
+        &self,
+        dir: P,
+        pred: impl Fn(&PakFileEntry) -> bool,
+        overwrite: bool,
+    ) -> Result<usize, PakError> {
+        let targets = self.extract_targets(dir.as_ref(), pred)?;
+        for (file, target) in targets.iter() {
+            extract_entry(file, target, overwrite)?;
+        }
+        Ok(targets.len())
+    }
+
+    /// Brings `dir` up to date with every entry, like `extract_to_dir` with
+    /// `overwrite`, but leaves files alone that already have the entry's size
+    /// and CRC32, so their mtimes don't change. Returns how many entries were
+    /// written and how many were skipped.
+    pub fn sync_to_dir<P: AsRef<path::Path>>(&self, dir: P) -> Result<(usize, usize), PakError> {
+        let targets = self.extract_targets(dir.as_ref(), |_| true)?;
+        let (mut written, mut skipped) = (0, 0);
+        for (file, target) in targets.iter() {
+            let unchanged = if file.is_dir_marker() {
+                target.is_dir()
+            } else {
+                match fs::metadata(target) {
+                    Ok(meta) if meta.is_file() && meta.len() == file.size as u64 => {
+                        crc32fast::hash(&fs::read(target)?) == file.crc32()
+                    }
+                    _ => false,
+                }
+            };
+            if unchanged {
+                skipped += 1;
+            } else {
+                extract_entry(file, target, true)?;
+                written += 1;
+            }
+        }
+        Ok((written, skipped))
+    }
+
+    /// Like `extract_to_dir` for every entry, writing files from `threads`
+    /// threads at once. Which files were written when one fails is undefined.
+    pub fn extract_all_parallel<P: AsRef<path::Path>>(
+        &self,
+        dir: P,
+        threads: usize,
+        overwrite: bool,
+    ) -> Result<usize, PakError> {
+        let targets = self.extract_targets(dir.as_ref(), |_| true)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(io::Error::other)?;
+        pool.install(|| {
+            targets
+                .par_iter()
+                .try_for_each(|(file, target)| extract_entry(file, target, overwrite))
+        })?;
+        Ok(targets.len())
+    }
+
+    /// Where each entry matching `pred` goes below `dir`, failing on names
+    /// that would escape it.
+    fn extract_targets(
+        &self,
+        dir: &path::Path,
+        pred: impl Fn(&PakFileEntry) -> bool,
+    ) -> Result<Vec<(&PakFileEntry, path::PathBuf)>, PakError> {
+        self.files
+            .iter()
+            .filter(|f| pred(f))
+            .map(|f| match Pak::relative_path(&f.name) {
+                Some(rel) => Ok((f, dir.join(rel))),
+                None => Err(PakError::InvalidName(f.name.clone())),
+            })
+            .collect()
+    }
+
+    /// Splits the entries, in table order, into Paks that each save to at
+    /// most `max_bytes`, header and table included. Entries are never split
+    /// across parts, so one that can't fit in a part by itself is an error.
+    /// The parts save like this Pak: same byte order, magic and table placement.
+    pub fn split_by_size(&self, max_bytes: u64) -> Result<Vec<Pak>, PakError> {
+        let mut parts: Vec<Pak> = Vec::new();
+        let mut part = self.empty_like();
+        let mut part_size = HEADER_SIZE as u64;
+        for file in self.files.iter() {
+            let needed = ENTRY_SIZE as u64 + file.size as u64;
+            if HEADER_SIZE as u64 + needed > max_bytes {
+                return Err(PakError::TooLarge(file.name.clone()));
+            }
+            if part_size + needed > max_bytes {
+                parts.push(std::mem::replace(&mut part, self.empty_like()));
+                part_size = HEADER_SIZE as u64;
+            }
+            part.files.push(file.clone());
+            part_size += needed;
+        }
+        if !part.files.is_empty() {
+            parts.push(part);
+        }
+        Ok(parts)
+    }
+
+    /// Renames every entry starting with `from` to start with `to` instead,
+    /// e.g. moving `textures/` to `tex/`. Nothing is renamed if any new name
+    /// would collide with another entry or be rejected by the same checks as
+    /// `add_file`'s. Returns how many entries were renamed.
+    pub fn rename_prefix(&mut self, from: &str, to: &str) -> Result<usize, PakError> {
+        self.forget_digests();
+        let renamed: Vec<Option<String>> = self
+            .files
+            .iter()
+            .map(|f| f.name.strip_prefix(from).map(|rest| self.checked_name(&format!("{}{}", to, rest))))
+            .map(Option::transpose)
+            .collect::<Result<_, _>>()?;
+        self.apply_renames(renamed)
+    }
+
+    /// Gives every entry with a `Some` in `renamed` that name, unless that
+    /// would leave two entries with the same name. Returns how many were
+    /// renamed.
+    fn apply_renames(&mut self, renamed: Vec<Option<String>>) -> Result<usize, PakError> {
+        let mut names: HashSet<&str> = HashSet::new();
+        for (file, new_name) in self.files.iter().zip(renamed.iter()) {
+            let name = new_name.as_deref().unwrap_or(&file.name);
+            if !names.insert(name) {
+                return Err(PakError::Duplicate(name.to_string()));
+            }
+        }
+
+        let mut count = 0;
+        for (file, new_name) in self.files.iter_mut().zip(renamed) {
+            if let Some(new_name) = new_name {
+                file.name = new_name;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Drops the leading `./` some packers put in front of every name, so
+    /// `./maps/e1m1.bsp` can be found as `maps/e1m1.bsp`. Only that one
+    /// leading `./` is touched; `.` components further in stay as they are.
+    /// Like `rename_prefix`, nothing changes if that would create a duplicate.
+    /// Returns how many entries were renamed.
+    pub fn canonicalize_names(&mut self) -> Result<usize, PakError> {
+        self.forget_digests();
+        let renamed: Vec<Option<String>> = self
+            .files
+            .iter()
+            .map(|f| match f.name.strip_prefix("./") {
+                Some("") => Err(PakError::InvalidName(f.name.clone())),
+                rest => Ok(rest.map(String::from)),
+            })
+            .collect::<Result<_, _>>()?;
+        self.apply_renames(renamed)
+    }
+
+    /// Moves every entry of `other` into this Pak, resolving name collisions
+    /// according to `on_conflict`. Offsets are recomputed when saving.
+    pub fn merge(&mut self, other: Pak, on_conflict: ConflictPolicy) -> Result<(), Box<dyn Error>> {
+        self.merge_files(other, on_conflict).map_err(|e| match e {
+            PakError::Duplicate(name) => Box::new(PakFileError {
+                msg: format!("File already exists: {}", name),
+            }) as Box<dyn Error>,
+            e => Box::new(e),
+        })
+    }
+
+    /// Puts split parts back together, merging them in order with
+    /// `on_conflict` deciding about names found in more than one part. The
+    /// result saves like the first part: same byte order, magic and table
+    /// placement.
+    pub fn join(parts: Vec<Pak>, on_conflict: ConflictPolicy) -> Result<Pak, PakError> {
+        let mut pak = parts.first().map(Pak::empty_like).unwrap_or_else(Pak::empty);
+        for part in parts {
+            pak.merge_files(part, on_conflict)?;
+        }
+        Ok(pak)
+    }
+
+    fn merge_files(&mut self, other: Pak, on_conflict: ConflictPolicy) -> Result<(), PakError> {
+        self.forget_digests();
+        if on_conflict == ConflictPolicy::Error {
+            if let Some(dup) = other
+                .files
+                .iter()
+                .find(|o| self.files.iter().any(|f| f.name.eq(&o.name)))
+            {
+                return Err(PakError::Duplicate(dup.name.clone()));
+            }
+        }
+
+        for file in other.files {
+            match (self.files.iter().position(|f| f.name.eq(&file.name)), on_conflict) {
+                (None, _) => self.files.push(file),
+                (Some(p), ConflictPolicy::Overwrite) => self.files[p] = file,
+                // Skip, or a duplicate within `other` itself: keep the first one.
+                (Some(_), _) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds `data` as `name` to the Pak file at `path` without rewriting what
+    /// is already there: the data goes after the existing data, followed by
+    /// a new file table, and the header is patched to point at it. If the old
+    /// table was the last thing in the file it gets overwritten, otherwise it
+    /// is left behind as a gap. Unlike `save_in_place` this patches the file
+    /// directly, so an interrupted append can leave it damaged. `name` is
+    /// cleaned up with `sanitize_name`, as `add_file` does.
+    pub fn append_in_place(path: String, name: String, data: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let name = Pak::sanitize_name(&name)?;
+        let mut f = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let len = f.metadata()?.len();
+        let mut buf = [0u8; HEADER_SIZE];
+        f.read_exact(&mut buf)?;
+        let header = PakHeader::from_u8(&buf)?;
+        check_table_fits(&header, len, ENTRY_SIZE)?;
+
+        let mut table = vec![0u8; header.size as usize];
+        f.seek(SeekFrom::Start(header.offset as u64))?;
+        f.read_exact(&mut table)?;
+        let files: Vec<PakFileEntry> = table
+            .chunks_exact(ENTRY_SIZE)
+            .map(|record| PakFileEntry::from_record(record, Endian::Little))
+            .collect();
+        check_table_placement(&header, &files)?;
+        if files.iter().any(|f| f.name == name) {
+            return Err(Box::new(PakError::Duplicate(name)));
+        }
+
+        let old_table = header.offset as u64..header.offset as u64 + table.len() as u64;
+        let data_end = files.iter().map(|f| f.offset as u64 + f.size as u64).fold(HEADER_SIZE as u64, u64::max);
+        let data_offset = if table.is_empty() {
+            data_end
+        } else if old_table.start >= data_end {
+            old_table.start
+        } else {
+            data_end.max(old_table.end)
+        };
+        let table_offset = data_offset + data.len() as u64;
+        if table_offset + (table.len() + ENTRY_SIZE) as u64 > u32::MAX as u64 {
+            return Err(Box::new(PakFileError {
+                msg: "Pak would grow past 4 GiB".to_string(),
+            }));
+        }
+
+        // Build the new record first so a bad name fails before anything is written.
+        let entry = PakFileEntry::new(name, data_offset as u32, data);
+        let mut record: Vec<u8> = Vec::with_capacity(ENTRY_SIZE);
+        entry.write_record_to(&mut record, entry.offset, entry.size, Endian::Little)?;
+
+        f.seek(SeekFrom::Start(data_offset))?;
+        entry.write_data(&mut f)?;
+        f.write_all(&table)?;
+        f.write_all(&record)?;
+        f.seek(SeekFrom::Start(4))?;
+        f.write_u32::<LittleEndian>(table_offset as u32)?;
+        f.write_u32::<LittleEndian>((table.len() + ENTRY_SIZE) as u32)?;
+        f.flush()?;
+        Ok(())
+    }
+
+    pub fn append_file(&mut self, infilepath: String, pakfilepath: String) -> Result<(), Box<dyn Error>> {
+        let newfilepath = path::Path::new(&infilepath);
+        if ! newfilepath.exists() {
+            return Err(Box::new(PakFileError{ msg: "File does not exist!".to_string() }))
+        }
+
+
+        let last_offset = fs::metadata(&self.pak_path)?.len() as u32;
+        let metadata = newfilepath.metadata()?;
+        let data = fs::read(&infilepath)?;
+
+        let mut fe = PakFileEntry::new(pakfilepath.to_string(), last_offset, data);
+        fe.mtime = Some(FileTime::from_last_modification_time(&metadata).unix_seconds());
+        fe.mode = file_mode(&metadata);
+        self.add_file(fe)?;
+        Ok(())
+    }
+}
+
+/// Reads the header and file table up front but leaves entry data on disk
+/// until it's asked for, then keeps it. Suits tools that browse a big Pak and
+/// only pull out a few files.
+pub struct PakReader<R: Read + Seek = File> {
+    reader: R,
+    /// Where the Pak starts in `reader`; offsets are relative to it.
+    base: u64,
+    header: PakHeader,
+    files: Vec<PakFileEntry>,
+    cache: HashMap<usize, Vec<u8>>,
+    reads: usize,
+}
+
+impl PakReader<File> {
+    pub fn open<P: AsRef<path::Path>>(path: P) -> Result<PakReader<File>, PakError> {
+        PakReader::new(File::open(path)?)
+    }
+}
+
+impl<R: Read + Seek> PakReader<R> {
+    /// Parses the header and table starting at the reader's current position.
+    pub fn new(mut reader: R) -> Result<PakReader<R>, PakError> {
+        let base = reader.stream_position()?;
+        let mut buf = [0u8; HEADER_SIZE];
+        read_exact_at(&mut reader, base, &mut buf).map_err(|_| PakError::Corrupt("file too small for header".to_string()))?;
+        let header = PakHeader::from_u8(&buf)?;
+        let len = reader.seek(SeekFrom::End(0))? - base;
+        check_table_fits(&header, len, ENTRY_SIZE)?;
+
+        let mut table = vec![0u8; header.size as usize];
+        read_exact_at(&mut reader, base + header.offset as u64, &mut table)?;
+        let files: Vec<PakFileEntry> = table
+            .chunks_exact(ENTRY_SIZE)
+            .map(|record| PakFileEntry::from_record(record, Endian::Little))
+            .collect();
+        check_table_placement(&header, &files)?;
+        // `data_for` allocates an entry's size before reading, so check it now.
+        if let Some(file) = files.iter().find(|f| f.offset as u64 + f.size as u64 > len) {
+            return Err(PakError::Corrupt(format!("data of {} runs past the end of the file", file.name)));
+        }
+
+        Ok(PakReader {
+            reader,
+            base,
+            header,
+            files,
+            cache: HashMap::new(),
+            reads: 0,
+        })
+    }
+
+    pub fn header(&self) -> &PakHeader {
+        &self.header
+    }
+
+    /// The table entries. Their data is always empty, use `data_for` to get it.
+    pub fn entries(&self) -> &[PakFileEntry] {
+        &self.files
+    }
+
+    /// The data of the entry called `name`, read from disk on the first call
+    /// and served from memory after that.
+    pub fn data_for(&mut self, name: &str) -> Result<&[u8], PakError> {
+        let i = self
+            .files
+            .iter()
+            .position(|f| f.name.eq(name))
+            .ok_or_else(|| PakError::NotFound(name.to_string()))?;
+        if !self.cache.contains_key(&i) {
+            let file = &self.files[i];
+            let mut data = vec![0u8; file.size as usize];
+            read_exact_at(&mut self.reader, self.base + file.offset as u64, &mut data).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    PakError::Corrupt(format!("data of {} runs past the end of the file", file.name))
+                }
+                _ => PakError::Io(e),
+            })?;
+            self.reads += 1;
+            self.cache.insert(i, data);
+        }
+        Ok(&self.cache[&i])
+    }
+
+    /// How many times entry data has been read from the underlying reader.
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Whether `bytes` start like a gzip or zstd stream.
+fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(GZIP_MAGIC) || bytes.starts_with(ZSTD_MAGIC)
+}
+
+/// Unpacks a gzip- or zstd-compressed Pak, going by the magic at the start.
+/// Anything else is passed through untouched. Offsets are 32-bit, so output
+/// past 4 GiB can't be a valid Pak and is rejected rather than read on.
+fn decompress(bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    if bytes.starts_with(GZIP_MAGIC) {
+        #[cfg(feature = "gzip")]
+        return read_capped(flate2::read::GzDecoder::new(bytes.as_slice()));
+        #[cfg(not(feature = "gzip"))]
+        return Err(io::Error::other("the Pak is gzip-compressed, which needs the gzip feature"));
+    }
+    if bytes.starts_with(ZSTD_MAGIC) {
+        #[cfg(feature = "zstd")]
+        return read_capped(zstd::stream::read::Decoder::new(bytes.as_slice())?);
+        #[cfg(not(feature = "zstd"))]
+        return Err(io::Error::other("the Pak is zstd-compressed, which needs the zstd feature"));
+    }
+    Ok(bytes)
+}
+
+/// Reads a decompressing `reader` to the end, failing past 4 GiB.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn read_capped<R: Read>(reader: R) -> io::Result<Vec<u8>> {
+    const MAX_SIZE: u64 = u32::MAX as u64;
+
+    let mut out: Vec<u8> = Vec::new();
+    reader.take(MAX_SIZE + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > MAX_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the decompressed Pak is larger than 4 GiB"));
+    }
+    Ok(out)
+}
+
+fn read_exact_at<R: Read + Seek>(reader: &mut R, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+    reader.seek(SeekFrom::Start(pos))?;
+    reader.read_exact(buf)
+}
+
+fn is_printable_ascii(name: &str) -> bool {
+    name.bytes().all(|b| (0x20..=0x7e).contains(&b))
+}
+
+/// Writes `file` to `target`, or creates `target` as a directory if the entry
+/// is a directory marker.
+fn extract_entry(file: &PakFileEntry, target: &path::Path, overwrite: bool) -> Result<(), PakError> {
+    if file.is_dir_marker() {
+        fs::create_dir_all(target)?;
+        return Ok(());
+    }
+    file.save_to(target.to_string_lossy().into_owned(), true, overwrite)?;
+    Ok(())
+}
+
+/// The permission bits of a file on Unix, `None` elsewhere.
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// The table record at `pos` in `bytes` for `Pak::repair_scan`, if it looks
+/// like a real one.
+fn plausible_record(bytes: &[u8], pos: usize) -> Option<PakFileEntry> {
+    let record = &bytes[pos..pos + ENTRY_SIZE];
+    // Cheap checks on the offset and size first, most positions fail them.
+    let offset = LittleEndian::read_u32(&record[NAME_SIZE..NAME_SIZE + 4]) as usize;
+    let size = LittleEndian::read_u32(&record[NAME_SIZE + 4..ENTRY_SIZE]) as usize;
+    let fits = offset >= HEADER_SIZE && offset + size <= bytes.len();
+    let clear = size == 0 || offset + size <= pos || offset >= pos + ENTRY_SIZE;
+    if !(fits && clear) {
+        return None;
+    }
+
+    let nul = record[..NAME_SIZE].iter().position(|&b| b == 0)?;
+    if !is_printable_ascii(std::str::from_utf8(&record[..nul]).ok()?) {
+        return None;
+    }
+    let entry = PakFileEntry::from_record(record, Endian::Little);
+    (Pak::sanitize_name(&entry.name).ok()? == entry.name).then_some(entry)
+}
+
+/// Rejects a header whose file table doesn't fit in a Pak of `len` bytes or
+/// isn't a whole number of `stride`-byte records, before its size is used to
+/// size any loop or allocation.
+fn check_table_fits(header: &PakHeader, len: u64, stride: usize) -> Result<(), PakError> {
+    if header.size as u64 > len || (header.size > 0 && header.offset as u64 + header.size as u64 > len) {
+        return Err(PakError::Corrupt("file table runs past the end of the file".to_string()));
+    }
+    if !header.size.is_multiple_of(stride as u32) {
+        return Err(PakError::Corrupt(format!(
+            "file table size {} is not a multiple of {}",
+            header.size, stride
+        )));
+    }
+    Ok(())
+}
+
+/// Where a loaded Pak keeps its file table: `Last` if it starts after the
+/// data of every entry, so rewrites can put it back there.
+fn table_layout_of(header: &PakHeader, files: &[PakFileEntry]) -> TableLayout {
+    let data_end = files.iter().filter(|f| f.size > 0).map(|f| f.offset as u64 + f.size as u64).max();
+    match data_end {
+        Some(end) if end <= header.offset as u64 => TableLayout::Last,
+        _ => TableLayout::First,
+    }
+}
+
+/// Rejects a file table that starts inside the header or overlaps the data
+/// of any entry, and entry data that starts inside the header. Empty entries
+/// hold no bytes, so their offset isn't checked. Run before any entry data is
+/// read.
+fn check_table_placement(header: &PakHeader, files: &[PakFileEntry]) -> Result<(), PakError> {
+    if header.offset < HEADER_SIZE as u32 {
+        return Err(PakError::Corrupt("file table overlaps the header".to_string()));
+    }
+
+    let table = header.offset as u64..header.offset as u64 + header.size as u64;
+    for file in files.iter().filter(|f| f.size > 0) {
+        let data = file.offset as u64..file.offset as u64 + file.size as u64;
+        if data.start < HEADER_SIZE as u64 {
+            return Err(PakError::Corrupt(format!("{} overlaps the header", file.name)));
+        }
+        if data.start < table.end && table.start < data.end {
+            return Err(PakError::Corrupt(format!("{} overlaps the file table", file.name)));
+        }
+    }
+    Ok(())
+}
+
+/// Digests for `Pak::find_by_sha256`: table indices by the SHA-256 of their data.
+#[derive(Debug)]
+struct HashIndex {
+    by_digest: HashMap<[u8; 32], Vec<usize>>,
+}
+
+/// Lazily built `HashIndex`. A clone starts out empty.
+#[derive(Debug, Default)]
+struct HashIndexCache(Mutex<Option<HashIndex>>);
+
+impl Clone for HashIndexCache {
+    fn clone(&self) -> Self {
+        HashIndexCache::default()
+    }
+}
+
+/// Layout choices for writing a Pak; every `save_*` variant maps onto these.
+#[derive(Debug, Clone)]
+struct SaveOptions {
+    alignment: u32,
+    deduplicate: bool,
+    /// Table indices in the order their data is written. Entries not listed
+    /// follow in table order.
+    data_order: Vec<usize>,
+    table_layout: TableLayout,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        SaveOptions {
+            alignment: 1,
+            deduplicate: false,
+            data_order: Vec::new(),
+            table_layout: TableLayout::First,
+        }
+    }
+}
+
+/// Picks entries by name with an optional include glob and any number of
+/// exclude globs; an entry passes if it's included and not excluded. A
+/// pattern without a `/` is matched against the last part of the name, so
+/// `*.wav` covers WAVs in every directory. Otherwise `*` stays within one
+/// directory and `**` crosses them, as in `Pak::matching`.
+#[derive(Debug, Clone, Default)]
+pub struct NameFilter {
+    include: Option<(GlobMatcher, bool)>,
+    exclude: Vec<(GlobMatcher, bool)>,
+}
+
+impl NameFilter {
+    pub fn new(include: Option<&str>, exclude: &[&str]) -> Result<NameFilter, PakError> {
+        let compile = |pattern: &str| Ok((compile_glob(pattern)?, !pattern.contains('/')));
+        Ok(NameFilter {
+            include: include.map(compile).transpose()?,
+            exclude: exclude.iter().map(|p| compile(p)).collect::<Result<_, PakError>>()?,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        let hit = |(glob, base_only): &(GlobMatcher, bool)| glob.is_match(if *base_only { file_name } else { name });
+        self.include.as_ref().is_none_or(hit) && !self.exclude.iter().any(hit)
+    }
+}
+
+fn compile_glob(pattern: &str) -> Result<GlobMatcher, PakError> {
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| PakError::InvalidPattern(e.to_string()))?
+        .compile_matcher())
+}
+
+/// Digest used by `Pak::checksum_manifest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+    Crc32,
+    Sha1,
+    Sha256,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Where `save_with_layout` puts the file table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableLayout {
+    /// Right after the header, followed by the data. What `save` writes
+    /// unless the Pak was loaded with its table last or
+    /// `Pak::set_file_table_offset_last` says otherwise.
+    #[default]
+    First,
+    /// After all data, as some of the original Quake tools wrote it.
+    Last,
+}
+
+/// What a `LayoutRegion` holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Header,
+    Table,
+    Data,
+    Gap,
+}
+
+impl std::fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self {
+            RegionKind::Header => "header",
+            RegionKind::Table => "table",
+            RegionKind::Data => "data",
+            RegionKind::Gap => "gap",
+        };
+        f.pad(kind)
+    }
+}
+
+/// Entry size figures, as returned by `Pak::size_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeStats {
+    pub files: usize,
+    pub total_bytes: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    /// The middle size, or the mean of the two middle ones for an even count.
+    pub median: f64,
+    /// Entry counts for sizes under 1 KiB, 1 to 16 KiB, 16 to 256 KiB and
+    /// 256 KiB or more.
+    pub histogram: [usize; 4],
+}
+
+impl SizeStats {
+    /// Where each histogram bucket after the first starts.
+    pub const BUCKET_LIMITS: [u64; 3] = [1024, 16 * 1024, 256 * 1024];
+}
+
+/// A byte range of a Pak file, as returned by `Pak::layout`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutRegion {
+    pub kind: RegionKind,
+    pub start: u64,
+    pub end: u64,
+    /// The entry name, for data regions.
+    pub name: Option<String>,
+}
+
+/// Changes between two Paks, from `Pak::diff_to_patch`. Changed entries
+/// carry their whole new data rather than a delta.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PakPatch {
+    pub added: Vec<(String, Vec<u8>)>,
+    pub changed: Vec<(String, Vec<u8>)>,
+    pub removed: Vec<String>,
+}
+
+impl PakPatch {
+    /// Magic at the start of a serialized patch.
+    pub const MAGIC: &'static [u8; 4] = b"PKPT";
+
+    /// Serializes the patch: `MAGIC`, then one frame per entry of a tag byte
+    /// (`A`dded, `C`hanged or `R`emoved), the name length as a little-endian
+    /// u16 and the name, then for added and changed entries the data length
+    /// as a little-endian u64 and the data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Self::MAGIC.to_vec();
+        let mut frame = |tag: u8, name: &str, data: Option<&[u8]>| {
+            bytes.push(tag);
+            bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            if let Some(data) = data {
+                bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(data);
+            }
+        };
+        for (name, data) in self.added.iter() {
+            frame(b'A', name, Some(data));
+        }
+        for (name, data) in self.changed.iter() {
+            frame(b'C', name, Some(data));
+        }
+        for name in self.removed.iter() {
+            frame(b'R', name, None);
+        }
+        bytes
+    }
+
+    /// Parses a patch written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<PakPatch, PakError> {
+        fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], PakError> {
+            if bytes.len() < len {
+                return Err(PakError::Corrupt("patch is cut short".to_string()));
+            }
+            let (head, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Ok(head)
+        }
+
+        let mut bytes = bytes;
+        if take(&mut bytes, 4)? != Self::MAGIC {
+            return Err(PakError::Corrupt("not a Pak patch".to_string()));
+        }
+        let mut patch = PakPatch::default();
+        while !bytes.is_empty() {
+            let tag = take(&mut bytes, 1)?[0];
+            if !matches!(tag, b'A' | b'C' | b'R') {
+                return Err(PakError::Corrupt(format!("unknown patch frame '{}'", tag.escape_ascii())));
+            }
+            let name_len = LittleEndian::read_u16(take(&mut bytes, 2)?) as usize;
+            let name = String::from_utf8(take(&mut bytes, name_len)?.to_vec())?;
+            if tag == b'R' {
+                patch.removed.push(name);
+                continue;
+            }
+            let data_len = LittleEndian::read_u64(take(&mut bytes, 8)?);
+            let data = take(&mut bytes, usize::try_from(data_len).unwrap_or(usize::MAX))?.to_vec();
+            if tag == b'A' {
+                patch.added.push((name, data));
+            } else {
+                patch.changed.push((name, data));
+            }
+        }
+        Ok(patch)
+    }
+}
+
+/// How `Pak::merge` handles an incoming entry whose name already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the existing entry and drop the incoming one.
+    Skip,
+    /// Replace the existing entry with the incoming one.
+    Overwrite,
+    /// Abort without changing anything.
+    Error,
+}
+
+impl std::fmt::Display for Pak { 
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<Pak structure from file {} with {} files>",
+            self.pak_path,
+            self.files.len()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct PakFileError {
+    pub msg: String,
+}
+
+impl std::fmt::Display for PakFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for PakFileError {}
+
+/// Errors from validating or restructuring a Pak.
+#[derive(Debug)]
+pub enum PakError {
+    /// The layout is inconsistent, e.g. overlapping or out-of-range regions.
+    Corrupt(String),
+    /// More than one entry has this name.
+    Duplicate(String),
+    /// The name is empty or doesn't fit the 56-byte name field.
+    InvalidName(String),
+    /// A table position past the end of the file table.
+    IndexOutOfRange(usize),
+    /// A glob pattern that doesn't parse.
+    InvalidPattern(String),
+    /// The entry is too big for the requested size limit on its own.
+    TooLarge(String),
+    /// No entry has this name.
+    NotFound(String),
+    /// Reading or writing the underlying file failed.
+    Io(io::Error),
+}
+
+impl std::fmt::Display for PakError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PakError::Corrupt(msg) => write!(f, "corrupt Pak: {}", msg),
+            PakError::Duplicate(name) => write!(f, "duplicate file entry: {}", name),
+            PakError::InvalidName(name) => write!(f, "invalid file name: '{}'", name),
+            PakError::IndexOutOfRange(index) => write!(f, "table index {} is out of range", index),
+            PakError::InvalidPattern(msg) => write!(f, "invalid pattern: {}", msg),
+            PakError::TooLarge(name) => write!(f, "{} doesn't fit in the size limit on its own", name),
+            PakError::NotFound(name) => write!(f, "file entry not found: {}", name),
+            PakError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for PakError {
+    fn from(e: io::Error) -> Self {
+        PakError::Io(e)
+    }
+}
+
+/// Names are the only strings in a Pak, so bytes that aren't UTF-8 make for
+/// an invalid name.
+impl From<std::string::FromUtf8Error> for PakError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        PakError::InvalidName(String::from_utf8_lossy(e.as_bytes()).into_owned())
+    }
+}
+
+impl Error for PakError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PakError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -1,10 +1,17 @@
-mod lib;
-use std::{error::Error};
+use std::{error::Error, io::Write, path::Path, sync::OnceLock, time::Duration};
 
-use lib::Pak;
+use rustpak::{format::{ListFormat, Listing}, shell::{Shell, Step}, watch::Repacker, ConflictPolicy, NameFilter, Pak, PakError, PakFileEntry, PakFileError, RecordLayout, NAME_SIZE};
 
 extern crate clap;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{debug, error, info, warn, LevelFilter};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+/// Names at least this long get flagged by `info`.
+const NAME_LENGTH_WARNING: usize = 48;
+
+/// Set from `--name-len`/`--entry-stride` for Paks from custom engines.
+static RECORD_LAYOUT: OnceLock<RecordLayout> = OnceLock::new();
 
 fn main() {
     let matches = App::new("Pak")
@@ -12,6 +19,51 @@ fn main() {
         .author("Sophie Luna Schumann <me@sophie.lgbt>")
         .about("Quake/Half-Life Pak file manipulation utility")
         .setting(clap::AppSettings::ArgRequiredElseHelp)
+        .arg(
+            Arg::with_name("verbose")
+                .help("Print more details, repeat for even more")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("Only print errors")
+                .short("q")
+                .long("quiet")
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("preserve-order")
+                .help("Keep existing entries at their table positions when rewriting a Pak, only moving their data (the default)")
+                .long("preserve-order")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-preserve-order")
+                .help("Allow rewrites that reorder the file table, like rebuild")
+                .long("no-preserve-order")
+                .conflicts_with("preserve-order")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("name-len")
+                .help("Size of the name field in file table records, for non-standard Paks [default: 56]")
+                .long("name-len")
+                .value_name("N")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("entry-stride")
+                .help("Size of a whole file table record, at least the name size plus 8 [default: name size + 8]")
+                .long("entry-stride")
+                .value_name("M")
+                .takes_value(true)
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("list")
                 .about("List files inside .pak")
@@ -20,6 +72,97 @@ fn main() {
                         .help("Path to .pak file")
                         .index(1)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("checksum")
+                        .help("Also print the CRC32 of each file")
+                        .long("checksum")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("output-format")
+                        .help("How to print the list")
+                        .long("output-format")
+                        .takes_value(true)
+                        .possible_values(&["plain", "csv", "json"])
+                        .default_value("plain"),
+                )
+                .arg(
+                    Arg::with_name("kind")
+                        .help("Also print whether each file looks like text or binary")
+                        .long("kind")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("sort-by")
+                        .help("Sort the list instead of printing it in table order; equal sizes sort by name")
+                        .long("sort-by")
+                        .takes_value(true)
+                        .possible_values(&["name", "size"]),
+                )
+                .arg(
+                    Arg::with_name("reverse")
+                        .help("Reverse the order of the list")
+                        .long("reverse")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("names-only")
+                        .help("Print just the file names, one per line, ignoring --output-format")
+                        .long("names-only")
+                        .required(false)
+                        .takes_value(false)
+                        .conflicts_with_all(&["checksum", "kind"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Show .pak header details")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("Show file sizes, a size histogram and counts per extension")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("layout")
+                .about("Show where the header, table, file data and gaps sit in the file")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("count")
+                .about("Print the number of files in Pak")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("Only count files matching this glob; without a '/' it matches file names in any directory")
+                        .long("pattern")
+                        .takes_value(true)
+                        .required(false),
                 ),
         )
         .subcommand(
@@ -50,11 +193,105 @@ fn main() {
                         .long("recursive")
                         .required(false)
                         .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("strip-components")
+                        .help("Drop this many leading directories from the file name when recreating its path")
+                        .long("strip-components")
+                        .takes_value(true)
+                        .value_name("N")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .help("Overwrite the output file if it already exists")
+                        .short("f")
+                        .long("force")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("strip-bom")
+                        .help("Remove the UTF-8 byte order mark from the start of text files")
+                        .long("strip-bom")
+                        .required(false)
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("extract-all")
+                .about("Extract every file from Pak, recreating the directory structure")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("outdir")
+                        .help("Directory to extract into")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("Only extract files matching this glob; without a '/' it matches file names in any directory")
+                        .long("pattern")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .help("Skip files matching this glob, after --pattern; can be given more than once")
+                        .long("exclude")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .help("Write files from this many threads at once")
+                        .long("threads")
+                        .takes_value(true)
+                        .value_name("N")
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .help("Overwrite files that already exist")
+                        .short("f")
+                        .long("force")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("strip-bom")
+                        .help("Remove the UTF-8 byte order mark from the start of text files")
+                        .long("strip-bom")
+                        .required(false)
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Print a file from Pak to stdout")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Filename to print")
+                        .index(2)
+                        .required(true),
                 ),
         )
         .subcommand(
         SubCommand::with_name("append")
-            .about("Append files to Pak")
+            .about("Append files to Pak, creating it if it doesn't exist")
             .arg(
                 Arg::with_name("pakfile")
                     .help("Path to .pak file")
@@ -66,75 +303,996 @@ fn main() {
                     .help("File to append")
                     .index(2)
                     .required(true),
+            )
+            .arg(
+                Arg::with_name("base-dir")
+                    .help("Store the file under its path relative to this directory")
+                    .long("base-dir")
+                    .takes_value(true)
+                    .required(false),
             ),
         )
+        .subcommand(
+            SubCommand::with_name("append-dir")
+                .about("Append every file below a directory to Pak, creating it if it doesn't exist")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .help("Directory to append")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("base-dir")
+                        .help("Store files under their paths relative to this directory instead of <dir>")
+                        .long("base-dir")
+                        .takes_value(true)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("touch")
+                .about("Add an empty file to Pak")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Filename to add")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Replace the contents of a file in Pak")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Filename inside the Pak to replace")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("File to read the new contents from")
+                        .index(3)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete a file from Pak")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Filename to delete")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Delete every file matching a pattern from Pak")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("pattern")
+                        .help("Glob of the files to delete; without a '/' it matches file names in any directory")
+                        .long("pattern")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export-zip")
+                .about("Convert Pak to a .zip archive")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("zipfile")
+                        .help("Path to the .zip file to create")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-zip")
+                .about("Build a Pak from a .zip archive")
+                .arg(
+                    Arg::with_name("zipfile")
+                        .help("Path to .zip file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to the .pak file to create")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rebuild")
+                .about("Rewrite Pak with a clean, sorted and gap-free layout; needs --no-preserve-order")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("outfile")
+                        .help("Path to save to, defaults to rewriting the Pak in place")
+                        .index(2)
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Recover files from a Pak with a damaged header by scanning for its file table")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to the damaged .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("outfile")
+                        .help("Path to save the recovered Pak to")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("split")
+                .about("Copy the files under a path prefix into a new Pak")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .help("Keep files whose name starts with this, e.g. maps/")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("outfile")
+                        .help("Path to the .pak file to create")
+                        .index(3)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("split-size")
+                .about("Split a Pak into numbered parts, e.g. foo.001.pak, each at most a given size")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("max-bytes")
+                        .help("Largest allowed size of each part in bytes")
+                        .index(2)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge several Paks into a new one")
+                .arg(
+                    Arg::with_name("outfile")
+                        .help("Path to the merged .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("pakfiles")
+                        .help("Paks to merge, in order")
+                        .index(2)
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("on-conflict")
+                        .help("What to do when a file exists in more than one Pak")
+                        .long("on-conflict")
+                        .takes_value(true)
+                        .possible_values(&["skip", "overwrite", "error"])
+                        .default_value("error"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("join")
+                .about("Reassemble a Pak from parts written by split-size")
+                .arg(
+                    Arg::with_name("outfile")
+                        .help("Path to the joined .pak file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("parts")
+                        .help("Part Paks, in order")
+                        .index(2)
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("on-conflict")
+                        .help("What to do when a file exists in more than one part")
+                        .long("on-conflict")
+                        .takes_value(true)
+                        .possible_values(&["skip", "overwrite", "error"])
+                        .default_value("error"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("Browse a Pak interactively with ls, cat, extract and find")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to .pak file")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Pack a directory into a Pak, and pack it again whenever a file in it changes")
+                .arg(
+                    Arg::with_name("pakfile")
+                        .help("Path to the .pak file to write, outside the watched directory")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .help("Directory to pack, file names are relative to it")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("debounce")
+                        .help("Milliseconds without changes to wait for before repacking")
+                        .long("debounce")
+                        .value_name("MS")
+                        .takes_value(true)
+                        .default_value("500"),
+                ),
+        )
         .get_matches();
 
+    let level = if matches.is_present("quiet") {
+        LevelFilter::Error
+    } else {
+        match matches.occurrences_of("verbose") {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|buf, record| match record.level() {
+            log::Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "{}: {}", level.as_str().to_lowercase(), record.args()),
+        })
+        .parse_default_env()
+        .init();
+
+    if matches.is_present("name-len") || matches.is_present("entry-stride") {
+        match record_layout(&matches) {
+            Ok(layout) => {
+                RECORD_LAYOUT.set(layout).unwrap();
+            }
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     if let Some(matches) = matches.subcommand_matches("list") {
         let pakfile = matches.value_of("pakfile").unwrap();
-        match list_pak_file(pakfile.to_string()) {
+        let format: ListFormat = matches.value_of("output-format").unwrap().parse().unwrap();
+        let mut listing = Listing::new(format);
+        listing.checksum = matches.is_present("checksum");
+        listing.kind = matches.is_present("kind");
+        let sort_by = matches.value_of("sort-by");
+        let listing = if matches.is_present("names-only") { None } else { Some(listing) };
+        match list_pak_file(pakfile.to_string(), listing, sort_by, matches.is_present("reverse")) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("info") {
+        let pakfile = matches.value_of("pakfile").unwrap();
+        match open_pak(pakfile.to_string()) {
+            Ok(pak) => {
+                println!("{}", pak.summary());
+                for (name, len) in pak.name_length_warnings(NAME_LENGTH_WARNING) {
+                    warn!("'{}' is {} bytes long, names can be at most {}", name, len, NAME_SIZE - 1);
+                }
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("stats") {
+        let pakfile = matches.value_of("pakfile").unwrap();
+        match print_pak_stats(pakfile.to_string()) {
             Ok(_) => {}
             Err(e) => {
-                eprintln!("Pak file error: {}", e)
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("layout") {
+        let pakfile = matches.value_of("pakfile").unwrap();
+        match print_pak_layout(pakfile.to_string()) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("count") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        match count_pak_files(pakfile, matches.value_of("pattern")) {
+            Ok(count) => println!("{}", count),
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
             }
         }
     } else if let Some(matches) = matches.subcommand_matches("extract") {
         let pakfile = matches.value_of("pakfile").unwrap().to_string();
         let path = matches.value_of("path").unwrap().to_string();
-        let mut outfile = path.to_string();
-        if let Some(option_outfile) = matches.value_of("outfile") {
-            outfile = option_outfile.clone().to_string();
-        }
+        let strip = match matches.value_of("strip-components").map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                error!("Invalid --strip-components: {}", e);
+                std::process::exit(1);
+            }
+            None => 0,
+        };
+        let outfile = matches.value_of("outfile").map(|o| o.to_string());
 
         let mut recursive = false;
         if matches.is_present("recursive") {
             recursive = true;
         }
 
-        match extract_file_from_pak_to_path(pakfile, path.clone(), outfile, recursive) {
+        let force = matches.is_present("force");
+
+        let strip_bom = matches.is_present("strip-bom");
+
+        match extract_file_from_pak_to_path(pakfile, path.clone(), outfile, recursive, force, strip, strip_bom) {
             Ok(finalpath) => {
-                eprintln!("Extracted: '{}' to '{}'", &path, finalpath)
+                info!("Extracted: '{}' to '{}'", &path, finalpath)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("extract-all") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let outdir = matches.value_of("outdir").unwrap().to_string();
+        let threads = match matches.value_of("threads").map(|n| n.parse::<usize>()) {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                error!("Invalid --threads: {}", e);
+                std::process::exit(1);
+            }
+            None => 1,
+        };
+        let excludes: Vec<&str> = matches.values_of("exclude").map(|v| v.collect()).unwrap_or_default();
+        let filter = match NameFilter::new(matches.value_of("pattern"), &excludes) {
+            Ok(filter) => filter,
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
             }
+        };
+        let (force, strip_bom) = (matches.is_present("force"), matches.is_present("strip-bom"));
+        match extract_all_from_pak(pakfile, outdir.clone(), &filter, threads, force, strip_bom) {
+            Ok(count) => {
+                info!("Extracted {} files to '{}'", count, outdir)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("cat") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let path = matches.value_of("path").unwrap().to_string();
+        match cat_file_from_pak(pakfile, path) {
+            Ok(_) => {}
             Err(e) => {
-                eprintln!("Pak file error: {}", e)
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
             }
         }
     } else if let Some(matches) = matches.subcommand_matches("append") {
-        add_file_to_pak(
-            matches.value_of("pakfile").unwrap().to_string(), 
-            matches.value_of("path").unwrap().to_string())
-            .unwrap();
+        let path = matches.value_of("path").unwrap().to_string();
+        let base_dir = matches.value_of("base-dir");
+        match add_file_to_pak(matches.value_of("pakfile").unwrap().to_string(), path.clone(), base_dir) {
+            Ok(name) if name == path => {
+                info!("Appended: '{}'", path)
+            }
+            Ok(name) => {
+                info!("Appended: '{}' as '{}'", path, name)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("append-dir") {
+        let dir = matches.value_of("dir").unwrap();
+        let base_dir = matches.value_of("base-dir").unwrap_or(dir);
+        match add_dir_to_pak(matches.value_of("pakfile").unwrap().to_string(), dir, base_dir) {
+            Ok(count) => {
+                info!("Appended {} files from '{}'", count, dir)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("touch") {
+        let path = matches.value_of("path").unwrap().to_string();
+        match add_empty_file_to_pak(matches.value_of("pakfile").unwrap().to_string(), path.clone()) {
+            Ok(_) => {
+                info!("Added empty file: '{}'", path)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("update") {
+        let path = matches.value_of("path").unwrap().to_string();
+        let source = matches.value_of("source").unwrap().to_string();
+        match update_file_in_pak(matches.value_of("pakfile").unwrap().to_string(), path.clone(), source) {
+            Ok(_) => {
+                info!("Updated: '{}'", path)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("delete") {
+        let path = matches.value_of("path").unwrap().to_string();
+        match delete_file_from_pak(matches.value_of("pakfile").unwrap().to_string(), path.clone()) {
+            Ok(_) => {
+                info!("Deleted: '{}'", path)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("prune") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        match prune_pak(pakfile, matches.value_of("pattern").unwrap()) {
+            Ok(count) => {
+                info!("Deleted {} files", count)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("export-zip") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let zipfile = matches.value_of("zipfile").unwrap().to_string();
+        match open_pak(pakfile).and_then(|pak| pak.export_zip(zipfile.clone())) {
+            Ok(_) => {
+                info!("Exported to '{}'", zipfile)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("import-zip") {
+        let zipfile = matches.value_of("zipfile").unwrap().to_string();
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        match Pak::from_zip(zipfile).and_then(|pak| pak.save(pakfile.clone())) {
+            Ok(_) => {
+                info!("Imported to '{}'", pakfile)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("rebuild") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let outfile = matches.value_of("outfile").map(|o| o.to_string());
+        if !matches.is_present("no-preserve-order") {
+            error!("rebuild sorts the file table by name, pass --no-preserve-order to allow that");
+            std::process::exit(1);
+        }
+        match rebuild_pak(pakfile, outfile) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("repair") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let outfile = matches.value_of("outfile").unwrap().to_string();
+        match repair_pak(pakfile, outfile.clone()) {
+            Ok(count) => {
+                info!("Recovered {} files into '{}'", count, outfile)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("split") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let prefix = matches.value_of("prefix").unwrap().to_string();
+        let outfile = matches.value_of("outfile").unwrap().to_string();
+        match split_pak(pakfile, prefix, outfile) {
+            Ok(count) => {
+                info!("Split off {} files", count)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("split-size") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        let result = matches
+            .value_of("max-bytes")
+            .unwrap()
+            .parse::<u64>()
+            .map_err(|e| e.into())
+            .and_then(|max_bytes| split_pak_by_size(pakfile, max_bytes));
+        match result {
+            Ok(count) => {
+                info!("Split into {} parts", count)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("merge") {
+        let pakfiles = matches
+            .values_of("pakfiles")
+            .unwrap()
+            .map(|p| p.to_string())
+            .collect();
+        match merge_paks(matches.value_of("outfile").unwrap().to_string(), pakfiles, conflict_policy(matches)) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("join") {
+        let parts = matches
+            .values_of("parts")
+            .unwrap()
+            .map(|p| p.to_string())
+            .collect();
+        match join_paks(matches.value_of("outfile").unwrap().to_string(), parts, conflict_policy(matches)) {
+            Ok(count) => {
+                info!("Joined {} files", count)
+            }
+            Err(e) => {
+                error!("Pak file error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("shell") {
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        if let Err(e) = run_shell(pakfile) {
+            error!("Pak file error: {}", e);
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("watch") {
+        let debounce = match matches.value_of("debounce").unwrap().parse::<u64>() {
+            Ok(ms) => Duration::from_millis(ms),
+            Err(e) => {
+                error!("Invalid --debounce: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let pakfile = matches.value_of("pakfile").unwrap().to_string();
+        if let Err(e) = watch_dir(pakfile, matches.value_of("dir").unwrap(), debounce) {
+            error!("Pak file error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_shell(pakfile: String) -> Result<(), Box<dyn Error>> {
+    let shell = Shell::new(open_pak(pakfile.clone())?);
+    info!("Loaded '{}' with {} files, type 'help' for commands", pakfile, shell.pak().files.len());
+    let mut editor = DefaultEditor::new()?;
+    let stdout = std::io::stdout();
+    loop {
+        let line = match editor.readline("pak> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        let mut out = stdout.lock();
+        match shell.execute(&line, &mut out) {
+            Ok(Step::Quit) => return Ok(()),
+            Ok(Step::Continue) => {}
+            Err(e) => error!("{}", e),
+        }
+        out.flush()?;
+    }
+}
+
+fn watch_dir(pakfile: String, dir: &str, debounce: Duration) -> Result<(), Box<dyn Error>> {
+    let dir = Path::new(dir).canonicalize()?;
+    let pakpath = Path::new(&pakfile);
+    let pakdir = match pakpath.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize()?,
+        _ => std::env::current_dir()?,
+    };
+    if pakdir.starts_with(&dir) {
+        return Err(format!("'{}' is inside the watched directory", pakfile).into());
+    }
+
+    let repacker = Repacker::new(&dir, pakfile.clone(), debounce);
+    info!("Packed {} files into '{}'", repacker.repack()?, pakfile);
+    repacker.run(|result| match result {
+        Ok(count) => info!("Repacked {} files into '{}'", count, pakfile),
+        Err(e) => error!("Pak file error: {}", e),
+    })?;
+    Ok(())
+}
+
+fn record_layout(matches: &ArgMatches) -> Result<RecordLayout, String> {
+    let parse = |arg: &str| match matches.value_of(arg).map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) => Ok(Some(n)),
+        Some(Err(e)) => Err(format!("Invalid --{}: {}", arg, e)),
+        None => Ok(None),
+    };
+    let name_len = parse("name-len")?.unwrap_or(NAME_SIZE);
+    let stride = parse("entry-stride")?.unwrap_or(name_len + 8);
+    RecordLayout::new(name_len, stride).ok_or_else(|| {
+        format!(
+            "--name-len {} with --entry-stride {} is not a usable record layout, the stride must be at least the name size plus 8",
+            name_len, stride
+        )
+    })
+}
+
+/// Loads a Pak, with the record layout from the command line if one was given.
+fn open_pak(path: String) -> Result<Pak, Box<dyn Error>> {
+    match RECORD_LAYOUT.get() {
+        Some(&layout) => Ok(Pak::from_file_with_layout(path, layout)?),
+        None => Pak::from_file(path),
+    }
+}
+
+/// Fails if the Pak at `path` is about to be rewritten in place while
+/// `--name-len`/`--entry-stride` are set. Saving always writes standard
+/// records, so this would silently convert a custom-engine Pak.
+fn check_rewritable(path: &str) -> Result<(), Box<dyn Error>> {
+    if RECORD_LAYOUT.get().is_some() {
+        return Err(Box::new(PakFileError {
+            msg: format!(
+                "'{}' can't be rewritten with --name-len/--entry-stride, only the standard layout can be written",
+                path
+            ),
+        }));
+    }
+    Ok(())
+}
+
+fn conflict_policy(matches: &ArgMatches) -> ConflictPolicy {
+    match matches.value_of("on-conflict").unwrap() {
+        "skip" => ConflictPolicy::Skip,
+        "overwrite" => ConflictPolicy::Overwrite,
+        _ => ConflictPolicy::Error,
     }
 }
 
+fn count_pak_files(pakfile: String, pattern: Option<&str>) -> Result<usize, Box<dyn Error>> {
+    let filter = NameFilter::new(pattern, &[])?;
+    let pak = open_pak(pakfile)?;
+    Ok(pak.files.iter().filter(|f| filter.matches(&f.name)).count())
+}
+
 fn extract_file_from_pak_to_path(
     pakfile: String,
     path: String,
-    outfile: String,
+    outfile: Option<String>,
     recursive: bool,
+    force: bool,
+    strip: usize,
+    strip_bom: bool,
 ) -> Result<String, Box<dyn Error>> {
-    let pak = Pak::from_file(pakfile)?;
-    match pak.files.iter().find(|pf| pf.name.eq(&path)) {
-        Some(pakfile) => match pakfile.save_to(outfile.to_string(), recursive) {
-            Ok(path) => Ok(path),
-            Err(e) => {
-                panic!("Pak error! {}", e)
-            }
-        },
+    let mut pak = open_pak(pakfile)?;
+    debug!("Loaded {}", pak);
+    let entry = pak.entry_mut(&path).ok_or_else(|| PakFileError {
+        msg: format!("file entry not found: {}", path),
+    })?;
+    if strip_bom {
+        strip_text_bom(entry);
+    }
+    let outfile = match outfile {
+        Some(outfile) => outfile,
+        None => {
+            let name = entry.stripped_name(strip).ok_or_else(|| PakFileError {
+                msg: format!("nothing left of '{}' after stripping {} components", path, strip),
+            })?;
+            let rel = Pak::relative_path(name).ok_or_else(|| PakError::InvalidName(name.to_string()))?;
+            rel.to_string_lossy().into_owned()
+        }
+    };
+    Ok(entry.save_to(outfile, recursive, force)?)
+}
+
+fn extract_all_from_pak(
+    pakfile: String,
+    outdir: String,
+    filter: &NameFilter,
+    threads: usize,
+    force: bool,
+    strip_bom: bool,
+) -> Result<usize, Box<dyn Error>> {
+    let mut pak = open_pak(pakfile)?.filter(|f| filter.matches(&f.name));
+    if strip_bom {
+        pak.files.iter_mut().for_each(strip_text_bom);
+    }
+    if threads > 1 {
+        Ok(pak.extract_all_parallel(outdir, threads, force)?)
+    } else {
+        Ok(pak.extract_to_dir(outdir, |_| true, force)?)
+    }
+}
+
+/// Drops the UTF-8 byte order mark from a text entry's data, if it has one.
+fn strip_text_bom(entry: &mut PakFileEntry) {
+    let bom_len = entry.data().len() - entry.data_without_bom().len();
+    if bom_len > 0 && entry.is_text() {
+        entry.data_mut().drain(..bom_len);
+    }
+}
+
+fn cat_file_from_pak(pakfile: String, path: String) -> Result<(), Box<dyn Error>> {
+    let pak = open_pak(pakfile)?;
+    let entry = pak.find(&path).ok_or_else(|| PakFileError {
+        msg: format!("file entry not found: {}", path),
+    })?;
+
+    let mut data: Vec<u8> = Vec::with_capacity(entry.size as usize);
+    entry.write_data(&mut data)?;
+    if !entry.is_text() {
+        warn!("'{}' looks like a binary file", path);
+    }
+    print!("{}", String::from_utf8_lossy(&data));
+    Ok(())
+}
+
+/// Prints the entries as `listing` says, or just their names without one.
+fn list_pak_file(pakfile: String, listing: Option<Listing>, sort_by: Option<&str>, reverse: bool) -> Result<(), Box<dyn Error>> {
+    let pak = open_pak(pakfile)?;
+
+    let mut files: Vec<&PakFileEntry> = pak.files.iter().collect();
+    match sort_by {
+        Some("size") => files.sort_by(|a, b| {
+            let by_size = if reverse { b.size.cmp(&a.size) } else { a.size.cmp(&b.size) };
+            by_size.then_with(|| a.name.cmp(&b.name))
+        }),
+        Some(_) if reverse => files.sort_by(|a, b| b.name.cmp(&a.name)),
+        Some(_) => files.sort_by(|a, b| a.name.cmp(&b.name)),
+        None if reverse => files.reverse(),
+        None => {}
+    }
+    match listing {
+        Some(listing) => listing.write(std::io::stdout().lock(), files)?,
         None => {
-            panic!("File not found in PakFile or other error!");
+            let mut out = std::io::stdout().lock();
+            for file in files {
+                writeln!(out, "{}", file.name)?;
+            }
         }
     }
+    Ok(())
+}
+
+fn print_pak_stats(pakfile: String) -> Result<(), Box<dyn Error>> {
+    const BUCKETS: [&str; 4] = ["< 1 KiB", "1-16 KiB", "16-256 KiB", ">= 256 KiB"];
+    let pak = open_pak(pakfile)?;
+    let stats = pak.size_stats();
+    println!("files: {}", stats.files);
+    println!("total: {} bytes", stats.total_bytes);
+    println!(
+        "size: min {} / max {} / mean {:.1} / median {:.1} bytes",
+        stats.min, stats.max, stats.mean, stats.median
+    );
+    for (label, count) in BUCKETS.iter().zip(stats.histogram.iter()) {
+        println!("{:>10}: {}", label, count);
+    }
+
+    let groups = pak.group_by_extension();
+    let mut extensions: Vec<&String> = groups.keys().collect();
+    extensions.sort();
+    for ext in extensions {
+        let name = if ext.is_empty() { "(none)" } else { ext.as_str() };
+        println!("{}: {}", name, groups[ext].len());
+    }
+    Ok(())
 }
 
-fn list_pak_file(pakfile: String) -> Result<(), Box<dyn Error>> {
-    let pak = Pak::from_file(pakfile)?;
-    pak.files.iter().for_each(|i| println!("{} - {} bytes", i.name, i.size));
+fn print_pak_layout(pakfile: String) -> Result<(), Box<dyn Error>> {
+    const BAR_WIDTH: u64 = 32;
+    let pak = open_pak(pakfile)?;
+    let regions = pak.layout();
+    let total = regions.iter().map(|r| r.end).max().unwrap_or(0).max(1);
+    for region in regions {
+        // Where the region sits in the file, scaled to the bar.
+        let from = region.start * BAR_WIDTH / total;
+        let to = (region.end * BAR_WIDTH).div_ceil(total).max(from + 1);
+        let bar: String = (0..BAR_WIDTH).map(|i| if i >= from && i < to { '#' } else { '.' }).collect();
+        let line = format!(
+            "{:>10} {:>10} {} {:<6} {}",
+            region.start,
+            region.end,
+            bar,
+            region.kind,
+            region.name.as_deref().unwrap_or("")
+        );
+        println!("{}", line.trim_end());
+    }
     Ok(())
 }
 
-fn add_file_to_pak(pakpath: String, filepath: String) -> Result<(), Box<dyn Error>> {
-    let mut pak = Pak::from_file(pakpath.clone())?;
-    pak.append_file(filepath.clone(), filepath)?;
-    pak.save(pakpath)
+fn add_file_to_pak(pakpath: String, filepath: String, base_dir: Option<&str>) -> Result<String, Box<dyn Error>> {
+    let name = match base_dir {
+        Some(base_dir) => PakFileEntry::name_relative_to(&filepath, base_dir)?,
+        None => filepath.clone(),
+    };
+    check_rewritable(&pakpath)?;
+    let mut pak = Pak::open_or_create(pakpath.clone())?;
+    pak.add_file(PakFileEntry::from_path(name.clone(), &filepath)?)?;
+    pak.save_in_place(pakpath)?;
+    Ok(name)
+}
+
+fn add_dir_to_pak(pakpath: String, dir: &str, base_dir: &str) -> Result<usize, Box<dyn Error>> {
+    check_rewritable(&pakpath)?;
+    let mut pak = Pak::open_or_create(pakpath.clone())?;
+    let count = pak.append_dir(dir, base_dir)?;
+    pak.save_in_place(pakpath)?;
+    Ok(count)
+}
+
+fn add_empty_file_to_pak(pakpath: String, filepath: String) -> Result<(), Box<dyn Error>> {
+    check_rewritable(&pakpath)?;
+    let mut pak = open_pak(pakpath.clone())?;
+    pak.add_empty(filepath)?;
+    pak.save_in_place(pakpath)
+}
+
+fn update_file_in_pak(pakpath: String, filepath: String, source: String) -> Result<(), Box<dyn Error>> {
+    check_rewritable(&pakpath)?;
+    let mut pak = open_pak(pakpath.clone())?;
+    pak.replace_file_from_path(&filepath, source)?;
+    pak.save_in_place(pakpath)
+}
+
+fn delete_file_from_pak(pakpath: String, filepath: String) -> Result<(), Box<dyn Error>> {
+    check_rewritable(&pakpath)?;
+    let mut pak = open_pak(pakpath.clone())?;
+    pak.remove_file(filepath)?;
+    pak.save_in_place(pakpath)
+}
+
+fn prune_pak(pakpath: String, pattern: &str) -> Result<usize, Box<dyn Error>> {
+    let filter = NameFilter::new(Some(pattern), &[])?;
+    check_rewritable(&pakpath)?;
+    let mut pak = open_pak(pakpath.clone())?;
+    let before = pak.files.len();
+    pak.retain(|f| !filter.matches(&f.name));
+    pak.save_in_place(pakpath)?;
+    Ok(before - pak.files.len())
+}
+
+fn rebuild_pak(pakfile: String, outfile: Option<String>) -> Result<(), Box<dyn Error>> {
+    if outfile.is_none() {
+        check_rewritable(&pakfile)?;
+    }
+    let pak = open_pak(pakfile.clone())?.rebuild()?;
+    match outfile {
+        Some(outfile) => pak.save(outfile),
+        None => pak.save_in_place(pakfile),
+    }
+}
+
+fn repair_pak(pakfile: String, outfile: String) -> Result<usize, Box<dyn Error>> {
+    let pak = Pak::repair_scan(&std::fs::read(pakfile)?);
+    pak.save(outfile)?;
+    Ok(pak.files.len())
+}
+
+fn split_pak(pakfile: String, prefix: String, outfile: String) -> Result<usize, Box<dyn Error>> {
+    let pak = open_pak(pakfile)?.filter(|f| f.name.starts_with(&prefix));
+    pak.save(outfile)?;
+    Ok(pak.files.len())
+}
+
+fn split_pak_by_size(pakfile: String, max_bytes: u64) -> Result<usize, Box<dyn Error>> {
+    let parts = open_pak(pakfile.clone())?.split_by_size(max_bytes)?;
+    let stem = pakfile.strip_suffix(".pak").unwrap_or(&pakfile);
+    for (i, part) in parts.iter().enumerate() {
+        let partfile = format!("{}.{:03}.pak", stem, i + 1);
+        debug!("Writing '{}' with {} files", partfile, part.files.len());
+        part.save(partfile)?;
+    }
+    Ok(parts.len())
+}
+
+fn join_paks(outfile: String, parts: Vec<String>, on_conflict: ConflictPolicy) -> Result<usize, Box<dyn Error>> {
+    let parts = parts
+        .into_iter()
+        .map(open_pak)
+        .collect::<Result<Vec<Pak>, Box<dyn Error>>>()?;
+    let pak = Pak::join(parts, on_conflict)?;
+    pak.save(outfile)?;
+    Ok(pak.files.len())
+}
+
+fn merge_paks(outfile: String, pakfiles: Vec<String>, on_conflict: ConflictPolicy) -> Result<(), Box<dyn Error>> {
+    let mut pak = Pak::empty();
+    for pakfile in pakfiles {
+        debug!("Merging '{}'", pakfile);
+        pak.merge(open_pak(pakfile)?, on_conflict)?;
+    }
+    pak.save(outfile)
 }
@@ -0,0 +1,96 @@
+//! Commands of the interactive `shell` subcommand. Reading lines is left to
+//! the binary; this only runs one line at a time against a loaded Pak.
+
+use std::{error::Error, io::Write};
+
+use crate::{Pak, PakError, PakFileEntry, PakFileError};
+
+const HELP: &str = "\
+ls [prefix]              list files, optionally only those under prefix
+cat <name>               print a file
+extract <name> [outfile] write a file out, to its own path by default
+find <glob>              list files matching a glob
+help                     show this help
+quit                     leave the shell";
+
+/// What to do after a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Continue,
+    Quit,
+}
+
+/// A Pak kept loaded between commands.
+#[derive(Debug)]
+pub struct Shell {
+    pak: Pak,
+}
+
+impl Shell {
+    pub fn new(pak: Pak) -> Shell {
+        Shell { pak }
+    }
+
+    pub fn pak(&self) -> &Pak {
+        &self.pak
+    }
+
+    /// Runs one command line, writing its output to `out`. Blank lines do
+    /// nothing. A failed command leaves the shell usable.
+    pub fn execute<W: Write>(&self, line: &str, out: &mut W) -> Result<Step, Box<dyn Error>> {
+        let line = line.trim();
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "" => {}
+            "ls" => {
+                for file in self.pak.files.iter().filter(|f| f.name.starts_with(arg)) {
+                    writeln!(out, "{} - {} bytes", file.name, file.size)?;
+                }
+            }
+            "cat" => out.write_all(self.entry(required(command, arg)?)?.data())?,
+            "extract" => {
+                let mut args = arg.split_whitespace();
+                let name = required(command, args.next().unwrap_or(""))?;
+                let outfile = match args.next() {
+                    Some(outfile) => outfile.to_string(),
+                    None => Pak::relative_path(name)
+                        .ok_or_else(|| PakError::InvalidName(name.to_string()))?
+                        .to_string_lossy()
+                        .into_owned(),
+                };
+                let written = self.entry(name)?.save_to(outfile, true, false)?;
+                writeln!(out, "Extracted '{}' to '{}'", name, written)?;
+            }
+            "find" => {
+                for file in self.pak.matching(required(command, arg)?)? {
+                    writeln!(out, "{}", file.name)?;
+                }
+            }
+            "help" => writeln!(out, "{}", HELP)?,
+            "quit" | "exit" => return Ok(Step::Quit),
+            _ => {
+                return Err(Box::new(PakFileError {
+                    msg: format!("unknown command '{}', try 'help'", command),
+                }))
+            }
+        }
+        Ok(Step::Continue)
+    }
+
+    fn entry(&self, name: &str) -> Result<&PakFileEntry, PakError> {
+        self.pak.find(name).ok_or_else(|| PakError::NotFound(name.to_string()))
+    }
+}
+
+fn required<'a>(command: &str, arg: &'a str) -> Result<&'a str, PakFileError> {
+    if arg.is_empty() {
+        return Err(PakFileError {
+            msg: format!("'{}' needs an argument, try 'help'", command),
+        });
+    }
+    Ok(arg)
+}
@@ -0,0 +1,93 @@
+//! Rebuilding a Pak from a source directory whenever something in it changes,
+//! for the `watch` subcommand.
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::Pak;
+
+/// Packs a directory into a Pak, again each time changes to it settle down.
+/// Changes are reported with `changed` and acted on by `poll`, so the
+/// debouncing works the same whether the changes come from `run` or not.
+#[derive(Debug)]
+pub struct Repacker {
+    dir: PathBuf,
+    pakfile: String,
+    debounce: Duration,
+    pending: Option<Instant>,
+}
+
+impl Repacker {
+    /// Repacks `dir` into `pakfile` once no change has been seen for
+    /// `debounce`. `pakfile` shouldn't be inside `dir`, or every repack
+    /// would trigger the next one.
+    pub fn new<P: AsRef<Path>>(dir: P, pakfile: String, debounce: Duration) -> Repacker {
+        Repacker {
+            dir: dir.as_ref().to_path_buf(),
+            pakfile,
+            debounce,
+            pending: None,
+        }
+    }
+
+    /// Packs every file under the directory, named relative to it, into a
+    /// fresh Pak that replaces `pakfile` atomically. Returns the number of
+    /// files packed.
+    pub fn repack(&self) -> Result<usize, Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        let count = pak.append_dir(&self.dir, &self.dir)?;
+        pak.save_atomic(self.pakfile.clone())?;
+        Ok(count)
+    }
+
+    /// Records a change seen `at`, restarting the debounce period.
+    pub fn changed(&mut self, at: Instant) {
+        self.pending = Some(at);
+    }
+
+    /// Repacks if a change is pending and nothing has changed since
+    /// `debounce` before `now`. `None` if there was nothing to do yet.
+    pub fn poll(&mut self, now: Instant) -> Option<Result<usize, Box<dyn Error>>> {
+        match self.pending {
+            Some(at) if now.duration_since(at) >= self.debounce => {
+                self.pending = None;
+                Some(self.repack())
+            }
+            _ => None,
+        }
+    }
+
+    /// Watches the directory and repacks after every burst of changes,
+    /// handing each result to `on_repack`. Only returns if the watch fails.
+    pub fn run(mut self, mut on_repack: impl FnMut(Result<usize, Box<dyn Error>>)) -> notify::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher.watch(&self.dir, RecursiveMode::Recursive)?;
+
+        loop {
+            let event = match self.pending {
+                Some(at) => rx.recv_timeout((at + self.debounce).saturating_duration_since(Instant::now())),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match event {
+                // Repacking reads every file, which shows up as access events.
+                Ok(event) => {
+                    if !event?.kind.is_access() {
+                        self.changed(Instant::now());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            if let Some(result) = self.poll(Instant::now()) {
+                on_repack(result);
+            }
+        }
+    }
+}
@@ -0,0 +1,390 @@
+#[cfg(test)]
+mod tests {
+    use rustpak::{Pak, PakFileEntry};
+    use std::error::Error;
+    use std::path::Path;
+    use std::process::{Command, Output};
+
+    fn rustpak(args: &[&str]) -> Output {
+        rustpak_in(Path::new("."), args)
+    }
+
+    fn rustpak_in(dir: &Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_rustpak"))
+            .current_dir(dir)
+            .args(args)
+            .env_remove("RUST_LOG")
+            .output()
+            .expect("failed to run rustpak")
+    }
+
+    #[test]
+    fn cli_log_levels() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("credits.txt");
+        let out = out.to_str().unwrap();
+
+        let default = rustpak(&["extract", "extras.pak", "credits.txt", out, "-r"]);
+        assert!(default.status.success());
+        assert!(String::from_utf8(default.stderr)?.contains("Extracted: 'credits.txt'"));
+
+        let quiet = rustpak(&["--quiet", "extract", "extras.pak", "credits.txt", out, "-r", "-f"]);
+        assert!(quiet.status.success());
+        assert!(quiet.stderr.is_empty());
+
+        let verbose = rustpak(&["-v", "extract", "extras.pak", "credits.txt", out, "-r", "-f"]);
+        assert!(verbose.status.success());
+        assert!(String::from_utf8(verbose.stderr)?.contains("Loaded <Pak structure"));
+
+        // Errors still get through with --quiet.
+        let failed = rustpak(&["-q", "extract", "extras.pak", "credits.txt", out, "-r"]);
+        assert!(String::from_utf8(failed.stderr)?.contains("error: Pak file error"));
+        Ok(())
+    }
+
+    #[test]
+    fn cli_list_checksum() -> Result<(), Box<dyn Error>> {
+        let plain = String::from_utf8(rustpak(&["list", "extras.pak"]).stdout)?;
+        assert!(plain.lines().any(|l| l == "fastdl.txt - 171 bytes"));
+
+        let output = String::from_utf8(rustpak(&["list", "--checksum", "extras.pak"]).stdout)?;
+        assert_eq!(output.lines().count(), 139);
+        assert!(output.lines().any(|l| l == "fastdl.txt - 171 bytes - crc32 e178f232"));
+        Ok(())
+    }
+
+    #[test]
+    fn cli_extract_strip_components() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("nested.pak");
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a/b/c.txt".to_string(), 0, b"c".to_vec()))?;
+        pak.save(pakfile.to_str().unwrap().to_string())?;
+
+        let pakfile = pakfile.to_str().unwrap();
+        let output = rustpak_in(dir.path(), &["extract", pakfile, "a/b/c.txt", "-r", "--strip-components", "1"]);
+        assert!(output.status.success());
+        assert_eq!(std::fs::read(dir.path().join("b/c.txt"))?, b"c");
+        assert!(!dir.path().join("a").exists());
+
+        let output = rustpak_in(dir.path(), &["extract", pakfile, "a/b/c.txt", "-r", "--strip-components", "3"]);
+        assert!(String::from_utf8(output.stderr)?.contains("nothing left of 'a/b/c.txt'"));
+
+        // A hostile name can't take the extraction outside the current directory.
+        let mut pak = Pak::empty();
+        pak.files.push(PakFileEntry::new("x/../../evil.txt".to_string(), 0, b"evil".to_vec()));
+        pak.save(pakfile.to_string())?;
+        std::fs::create_dir(dir.path().join("work"))?;
+        for strip in ["0", "1"] {
+            let args = ["extract", pakfile, "x/../../evil.txt", "-r", "--strip-components", strip];
+            let output = rustpak_in(&dir.path().join("work"), &args);
+            assert!(!output.status.success());
+            assert!(String::from_utf8(output.stderr)?.contains("invalid file name"));
+        }
+        assert!(!dir.path().join("evil.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn cli_count() -> Result<(), Box<dyn Error>> {
+        let all = rustpak(&["count", "extras.pak"]);
+        assert!(all.status.success());
+        assert_eq!(String::from_utf8(all.stdout)?, "139\n");
+
+        let touch = rustpak(&["count", "extras.pak", "--pattern", "touch_default/**"]);
+        assert_eq!(String::from_utf8(touch.stdout)?, "35\n");
+
+        let none = rustpak(&["count", "extras.pak", "--pattern", "*.nothing"]);
+        assert!(none.status.success());
+        assert_eq!(String::from_utf8(none.stdout)?, "0\n");
+
+        // Like prune and extract-all, a pattern without '/' matches in every directory.
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let wavs = pak.files.iter().filter(|f| f.name.ends_with(".wav")).count();
+        assert!(wavs > 0 && pak.files.iter().any(|f| f.name.contains('/') && f.name.ends_with(".wav")));
+        let wav = rustpak(&["count", "extras.pak", "--pattern", "*.wav"]);
+        assert_eq!(String::from_utf8(wav.stdout)?, format!("{}\n", wavs));
+        Ok(())
+    }
+
+    #[test]
+    fn cli_list_sort_by_size() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("sizes.pak");
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![0; 2]))?;
+        pak.add_file(PakFileEntry::new("c.txt".to_string(), 0, vec![0; 5]))?;
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![0; 2]))?;
+        pak.save(pakfile.to_str().unwrap().to_string())?;
+
+        let pakfile = pakfile.to_str().unwrap();
+        let output = rustpak(&["list", pakfile, "--sort-by", "size", "--reverse"]);
+        let lines: Vec<String> = String::from_utf8(output.stdout)?.lines().map(String::from).collect();
+        assert_eq!(lines, vec!["c.txt - 5 bytes", "a.txt - 2 bytes", "b.txt - 2 bytes"]);
+
+        let output = rustpak(&["list", pakfile, "--sort-by", "name"]);
+        let first = String::from_utf8(output.stdout)?.lines().next().map(String::from);
+        assert_eq!(first.as_deref(), Some("a.txt - 2 bytes"));
+        Ok(())
+    }
+
+    #[test]
+    fn cli_append_base_dir() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("base.pak");
+        Pak::empty().save(pakfile.to_str().unwrap().to_string())?;
+        let assets = dir.path().join("assets");
+        std::fs::create_dir_all(assets.join("maps"))?;
+        let bsp = assets.join("maps/e1m1.bsp");
+        std::fs::write(&bsp, [1, 2, 3])?;
+
+        let output = rustpak(&[
+            "append",
+            pakfile.to_str().unwrap(),
+            bsp.to_str().unwrap(),
+            "--base-dir",
+            assets.to_str().unwrap(),
+        ]);
+        assert!(output.status.success());
+
+        let pak = Pak::from_file(pakfile.to_str().unwrap().to_string())?;
+        assert_eq!(pak.files[0].name, "maps/e1m1.bsp");
+        assert_eq!(pak.files[0].data(), &[1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn cli_extract_all_exclude() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("out");
+        let output = rustpak(&["extract-all", "extras.pak", out.to_str().unwrap(), "--exclude", "*.wav"]);
+        assert!(output.status.success());
+
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let wavs = pak.files.iter().filter(|f| f.name.ends_with(".wav")).count();
+        assert!(wavs > 0);
+        assert!(out.join("fastdl.txt").exists());
+        for f in &pak.files {
+            assert_eq!(out.join(&f.name).exists(), !f.name.ends_with(".wav"), "{}", f.name);
+        }
+
+        let out = dir.path().join("touch");
+        let output = rustpak(&[
+            "extract-all",
+            "extras.pak",
+            out.to_str().unwrap(),
+            "--pattern",
+            "touch_default/**",
+            "--exclude",
+            "*.tga",
+            "--exclude",
+            "*.txt",
+        ]);
+        assert!(output.status.success());
+        let names: Vec<String> = std::fs::read_dir(out.join("touch_default"))?
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert!(!names.is_empty());
+        assert!(names.iter().all(|n| !n.ends_with(".tga") && !n.ends_with(".txt")), "{:?}", names);
+        assert!(!out.join("fastdl.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn cli_extract_all_dir_markers() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("dirs.pak");
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("maps/".to_string(), 0, Vec::new()))?;
+        pak.add_file(PakFileEntry::new("sound/".to_string(), 0, Vec::new()))?;
+        pak.add_file(PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![1, 2, 3]))?;
+        pak.save(pakfile.to_str().unwrap().to_string())?;
+
+        for threads in ["1", "2"] {
+            let out = dir.path().join(format!("out{}", threads));
+            let output = rustpak(&["extract-all", pakfile.to_str().unwrap(), out.to_str().unwrap(), "--threads", threads]);
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            assert!(out.join("maps").is_dir());
+            assert!(out.join("sound").is_dir());
+            assert_eq!(std::fs::read(out.join("maps/e1m1.bsp"))?, [1, 2, 3]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cli_list_names_only() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let output = rustpak(&["list", "extras.pak", "--names-only"]);
+        assert!(output.status.success());
+        let names: Vec<&str> = pak.entry_names().collect();
+        assert_eq!(String::from_utf8(output.stdout)?, format!("{}\n", names.join("\n")));
+
+        let output = rustpak(&["list", "extras.pak", "--names-only", "--sort-by", "name"]);
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(String::from_utf8(output.stdout)?.lines().collect::<Vec<_>>(), sorted);
+
+        assert!(!rustpak(&["list", "extras.pak", "--names-only", "--checksum"]).status.success());
+        Ok(())
+    }
+
+    #[test]
+    fn cli_prune() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("prune.pak");
+        std::fs::copy("extras.pak", &pakfile)?;
+        let pakfile = pakfile.to_str().unwrap();
+
+        let output = rustpak(&["prune", pakfile, "--pattern", "*.wav"]);
+        assert!(output.status.success());
+        let pak = Pak::from_file(pakfile.to_string())?;
+        let original = Pak::from_file("extras.pak".to_string())?;
+        let wavs = original.files.iter().filter(|f| f.name.ends_with(".wav")).count();
+        assert_eq!(pak.files.len(), original.files.len() - wavs);
+        assert!(pak.files.iter().all(|f| !f.name.ends_with(".wav")));
+        Ok(())
+    }
+
+    #[test]
+    fn cli_append_creates_pak() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("readme.txt"), b"hi")?;
+
+        let output = rustpak_in(dir.path(), &["append", "new.pak", "readme.txt"]);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let pak = Pak::from_file(dir.path().join("new.pak").to_str().unwrap().to_string())?;
+        assert_eq!(pak.files[0].name, "readme.txt");
+        assert_eq!(pak.files[0].data(), b"hi");
+        Ok(())
+    }
+
+    #[test]
+    fn cli_custom_record_layout() -> Result<(), Box<dyn Error>> {
+        // Two entries in 40-byte records with 32-byte names, table last.
+        let mut bytes: Vec<u8> = b"PACK".to_vec();
+        bytes.extend_from_slice(&20u32.to_le_bytes());
+        bytes.extend_from_slice(&80u32.to_le_bytes());
+        bytes.extend_from_slice(b"Hello");
+        bytes.extend_from_slice(&[1, 2, 3]);
+        for (name, offset, size) in [("a.txt", 12u32, 5u32), ("maps/b.bsp", 17, 3)] {
+            let mut field = [0u8; 32];
+            field[..name.len()].copy_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&field);
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&size.to_le_bytes());
+        }
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("custom.pak");
+        std::fs::write(&pakfile, bytes)?;
+        let pakfile = pakfile.to_str().unwrap();
+
+        let output = rustpak(&["list", pakfile, "--name-len", "32", "--entry-stride", "40"]);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout)?, "a.txt - 5 bytes\nmaps/b.bsp - 3 bytes\n");
+
+        let output = rustpak(&["--name-len", "32", "cat", pakfile, "a.txt"]);
+        assert_eq!(output.stdout, b"Hello");
+
+        // Saving would write standard records, so rewriting in place is refused.
+        let before = std::fs::read(pakfile)?;
+        std::fs::write(dir.path().join("c.txt"), b"c")?;
+        for args in [
+            vec!["delete", pakfile, "a.txt"],
+            vec!["append", pakfile, "c.txt"],
+            vec!["rebuild", pakfile, "--no-preserve-order"],
+        ] {
+            let output = rustpak_in(dir.path(), &[&args[..], &["--name-len", "32", "--entry-stride", "40"]].concat());
+            assert!(!output.status.success(), "{:?}", args);
+            assert!(String::from_utf8(output.stderr)?.contains("can't be rewritten"));
+        }
+        assert_eq!(std::fs::read(pakfile)?, before);
+
+        let output = rustpak(&["list", pakfile, "--name-len", "32", "--entry-stride", "39"]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)?.contains("not a usable record layout"));
+        Ok(())
+    }
+
+    #[test]
+    fn cli_extract_strip_bom() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("bom.pak");
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("bom.cfg".to_string(), 0, b"\xEF\xBB\xBFbind w +forward\n".to_vec()))?;
+        pak.add_file(PakFileEntry::new("plain.cfg".to_string(), 0, b"bind s +back\n".to_vec()))?;
+        pak.save(pakfile.to_str().unwrap().to_string())?;
+        assert_eq!(pak.files[0].data_without_bom(), b"bind w +forward\n");
+        assert_eq!(pak.files[1].data_without_bom(), b"bind s +back\n");
+        let pakfile = pakfile.to_str().unwrap();
+
+        let out = dir.path().join("out");
+        let output = rustpak(&["extract-all", pakfile, out.to_str().unwrap(), "--strip-bom"]);
+        assert!(output.status.success());
+        assert_eq!(std::fs::read(out.join("bom.cfg"))?, b"bind w +forward\n");
+        assert_eq!(std::fs::read(out.join("plain.cfg"))?, b"bind s +back\n");
+
+        let output = rustpak_in(dir.path(), &["extract", pakfile, "bom.cfg", "kept.cfg"]);
+        assert!(output.status.success());
+        assert_eq!(std::fs::read(dir.path().join("kept.cfg"))?, b"\xEF\xBB\xBFbind w +forward\n");
+        let output = rustpak_in(dir.path(), &["extract", pakfile, "bom.cfg", "stripped.cfg", "--strip-bom"]);
+        assert!(output.status.success());
+        assert_eq!(std::fs::read(dir.path().join("stripped.cfg"))?, b"bind w +forward\n");
+        Ok(())
+    }
+
+    #[test]
+    fn cli_rewrites_preserve_order() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pakfile = dir.path().join("order.pak");
+        std::fs::copy("extras.pak", &pakfile)?;
+        std::fs::write(dir.path().join("zzz.txt"), b"last")?;
+        std::fs::write(dir.path().join("aaa.txt"), b"first?")?;
+        let original: Vec<String> = Pak::from_file("extras.pak".to_string())?.entry_names().map(String::from).collect();
+        let pakfile = pakfile.to_str().unwrap();
+
+        assert!(rustpak_in(dir.path(), &["append", pakfile, "zzz.txt"]).status.success());
+        assert!(rustpak_in(dir.path(), &["append", pakfile, "aaa.txt", "--preserve-order"]).status.success());
+        assert!(rustpak_in(dir.path(), &["update", pakfile, "fastdl.txt", "zzz.txt"]).status.success());
+        let names: Vec<String> = Pak::from_file(pakfile.to_string())?.entry_names().map(String::from).collect();
+        assert_eq!(names[..original.len()], original[..]);
+        assert_eq!(names[original.len()..], ["zzz.txt", "aaa.txt"]);
+
+        let output = rustpak(&["rebuild", pakfile]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8(output.stderr)?.contains("--no-preserve-order"));
+        assert_eq!(Pak::from_file(pakfile.to_string())?.entry_names().collect::<Vec<_>>(), names);
+
+        assert!(rustpak(&["rebuild", pakfile, "--no-preserve-order"]).status.success());
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(Pak::from_file(pakfile.to_string())?.entry_names().collect::<Vec<_>>(), sorted);
+        Ok(())
+    }
+
+    #[test]
+    fn cli_errors_exit_nonzero() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let merged = dir.path().join("m.pak");
+        let merged = merged.to_str().unwrap();
+        let zipfile = dir.path().join("missing.zip");
+        let zipfile = zipfile.to_str().unwrap();
+
+        for args in [
+            &["merge", merged, "extras.pak", "extras.pak"][..],
+            &["list", "missing.pak"],
+            &["info", "missing.pak"],
+            &["stats", "missing.pak"],
+            &["layout", "missing.pak"],
+            &["extract", "extras.pak", "missing.txt", merged],
+            &["cat", "extras.pak", "missing.txt"],
+            &["export-zip", "missing.pak", zipfile],
+            &["import-zip", zipfile, merged],
+            &["split", "missing.pak", "maps/", merged],
+        ] {
+            let output = rustpak(args);
+            assert!(!output.status.success(), "{:?} succeeded", args);
+            assert!(String::from_utf8(output.stderr)?.contains("error: Pak file error"));
+        }
+        Ok(())
+    }
+}
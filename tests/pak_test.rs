@@ -1,7 +1,11 @@
 #[cfg(test)]
 mod tests {
-    use rustpak::{Pak, PakFileEntry, PakFileError};
+    use rustpak::format::{ListFormat, Listing};
+    use rustpak::shell::{Shell, Step};
+    use rustpak::watch::Repacker;
+    use rustpak::{ConflictPolicy, Endian, HashAlgo, Pak, PakError, PakFileEntry, PakFileError, PakPatch, PakReader, RegionKind, TableLayout};
     use std::error::Error;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn pak_from_file() -> Result<(), Box<dyn Error>> {
@@ -14,7 +18,7 @@ mod tests {
 
     #[test]
     fn pak_add_file() -> Result<(), Box<dyn Error>> {
-        let mut pak = Pak::new();
+        let mut pak = Pak::empty();
         match pak.add_file(PakFileEntry::new("test.txt".to_string(), 0, vec![b'H'])) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
@@ -23,7 +27,7 @@ mod tests {
 
     #[test]
     fn pak_add_duplicate_file() -> Result<(), Box<dyn Error>> {
-        let mut pak = Pak::new();
+        let mut pak = Pak::empty();
         pak.add_file(PakFileEntry::new("test.txt".to_string(), 0, vec![b'H']))
             .unwrap();
         let result = pak.add_file(PakFileEntry::new("test.txt".to_string(), 0, vec![b'H']));
@@ -38,7 +42,7 @@ mod tests {
 
     #[test]
     fn pak_delete_file() -> Result<(), Box<dyn Error>> {
-        let mut pak = Pak::new();
+        let mut pak = Pak::empty();
         pak.add_file(PakFileEntry::new("test.txt".to_string(), 0, vec![b'H']))
             .unwrap();
         pak.remove_file("test.txt".to_string())
@@ -46,8 +50,8 @@ mod tests {
 
     #[test]
     #[should_panic]
-    fn pak_delete_file_nonexisting() -> () {
-        let mut pak = Pak::new();
+    fn pak_delete_file_nonexisting() {
+        let mut pak = Pak::empty();
         pak.add_file(PakFileEntry::new("test.txt".to_string(), 0, vec![b'H']))
             .unwrap();
         pak.remove_file("doesnotexist.txt".to_string()).unwrap();
@@ -55,9 +59,2125 @@ mod tests {
 
     #[test]
     fn pak_save() -> Result<(), Box<dyn Error>> {
-        let mut pak = Pak::new();
+        let mut pak = Pak::empty();
         pak.add_file(PakFileEntry::new("test.txt".to_string(), 12+64, "Hello World".as_bytes().to_vec()))
             .unwrap();
         pak.save("test.pak".to_string())
     }
+
+    fn merge_fixture() -> (Pak, Pak) {
+        let mut base = Pak::empty();
+        base.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A']))
+            .unwrap();
+        base.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B']))
+            .unwrap();
+
+        let mut other = Pak::empty();
+        other.add_file(PakFileEntry::new("b.txt".to_string(), 0, "BB".as_bytes().to_vec()))
+            .unwrap();
+        other.add_file(PakFileEntry::new("c.txt".to_string(), 0, vec![b'C']))
+            .unwrap();
+        (base, other)
+    }
+
+    #[test]
+    fn pak_merge_skip() -> Result<(), Box<dyn Error>> {
+        let (mut base, other) = merge_fixture();
+        base.merge(other, ConflictPolicy::Skip)?;
+        let names: Vec<&str> = base.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(base.files[1].size, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_merge_overwrite() -> Result<(), Box<dyn Error>> {
+        let (mut base, other) = merge_fixture();
+        base.merge(other, ConflictPolicy::Overwrite)?;
+        let names: Vec<&str> = base.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "b.txt", "c.txt"]);
+        assert_eq!(base.files[1].size, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_merge_error() {
+        let (mut base, other) = merge_fixture();
+        assert!(base.merge(other, ConflictPolicy::Error).is_err());
+        // Nothing is moved when the merge is rejected.
+        assert_eq!(base.files.len(), 2);
+    }
+
+    #[test]
+    fn pak_summary() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let summary = pak.summary();
+        assert!(summary.contains("Magic: PACK"));
+        assert!(summary.contains("Entries: 139"));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_save_to_refuses_existing() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("test.txt");
+        std::fs::write(&target, "old")?;
+
+        let entry = PakFileEntry::new("test.txt".to_string(), 0, "new".as_bytes().to_vec());
+        let result = entry.save_to(target.to_str().unwrap().to_string(), true, false);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&target)?, "old");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_save_to_force_overwrites() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let target = dir.path().join("test.txt");
+        std::fs::write(&target, "old")?;
+
+        let entry = PakFileEntry::new("test.txt".to_string(), 0, "new".as_bytes().to_vec());
+        entry.save_to(target.to_str().unwrap().to_string(), true, true)?;
+        assert_eq!(std::fs::read_to_string(&target)?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_extension() {
+        let entry = PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![]);
+        assert_eq!(entry.extension(), Some("bsp"));
+        let entry = PakFileEntry::new("maps.d/README".to_string(), 0, vec![]);
+        assert_eq!(entry.extension(), None);
+    }
+
+    #[test]
+    fn pak_group_by_extension() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![]))?;
+        pak.add_file(PakFileEntry::new("maps/e1m2.bsp".to_string(), 0, vec![]))?;
+        pak.add_file(PakFileEntry::new("gfx/palette.lmp".to_string(), 0, vec![]))?;
+        pak.add_file(PakFileEntry::new("README".to_string(), 0, vec![]))?;
+
+        let groups = pak.group_by_extension();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups["bsp"].len(), 2);
+        assert_eq!(groups["lmp"][0].name, "gfx/palette.lmp");
+        assert_eq!(groups[""][0].name, "README");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_delete_file_and_resave() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, "Hello".as_bytes().to_vec()))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, "World".as_bytes().to_vec()))?;
+        pak.save(path.clone())?;
+
+        let mut pak = Pak::from_file(path.clone())?;
+        pak.remove_file("a.txt".to_string())?;
+        pak.save(path.clone())?;
+
+        let pak = Pak::from_file(path)?;
+        assert_eq!(pak.files.len(), 1);
+        assert_eq!(pak.files[0].name, "b.txt");
+        assert_eq!(pak.files[0].offset, 12 + 64);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_in_place() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("big.txt".to_string(), 0, vec![b'x'; 4096]))?;
+        pak.save(path.clone())?;
+
+        let mut pak = Pak::from_file(path.clone())?;
+        pak.remove_file("big.txt".to_string())?;
+        pak.add_file(PakFileEntry::new("small.txt".to_string(), 0, vec![b'y'; 4]))?;
+        pak.save_in_place(path.clone())?;
+
+        // Only the rewritten Pak is left, no stray temp files.
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        assert_eq!(std::fs::metadata(&path)?.len(), 12 + 64 + 4);
+        let pak = Pak::from_file(path)?;
+        assert_eq!(pak.files.len(), 1);
+        assert_eq!(pak.files[0].name, "small.txt");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pak_save_in_place_keeps_permissions() -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, b"a".to_vec()))?;
+        pak.save(path.clone())?;
+
+        for mode in [0o644, 0o640] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+            pak.save_in_place(path.clone())?;
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, mode);
+            pak.save_atomic(path.clone())?;
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, mode);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_ffi_open_extract_free() -> Result<(), Box<dyn Error>> {
+        use rustpak::ffi::*;
+        use std::ffi::CString;
+
+        let dir = tempfile::tempdir()?;
+        let dest = CString::new(dir.path().join("credits.txt").to_str().unwrap())?;
+        let path = CString::new("extras.pak")?;
+        let name = CString::new("credits.txt")?;
+        let missing = CString::new("doesnotexist.txt")?;
+
+        unsafe {
+            let mut pak: *mut Pak = std::ptr::null_mut();
+            assert_eq!(rustpak_open(path.as_ptr(), &mut pak), RUSTPAK_OK);
+            assert!(!pak.is_null());
+
+            let mut count = 0usize;
+            assert_eq!(rustpak_entry_count(pak, &mut count), RUSTPAK_OK);
+            assert_eq!(count, 139);
+
+            assert_eq!(rustpak_extract(pak, name.as_ptr(), dest.as_ptr(), false), RUSTPAK_OK);
+            assert_eq!(
+                rustpak_extract(pak, missing.as_ptr(), dest.as_ptr(), false),
+                RUSTPAK_ERR_NOT_FOUND
+            );
+            assert_eq!(rustpak_entry_count(std::ptr::null(), &mut count), RUSTPAK_ERR_NULL);
+            rustpak_free(pak);
+        }
+
+        assert_eq!(std::fs::metadata(dir.path().join("credits.txt"))?.len(), 594);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_export_zip() -> Result<(), Box<dyn Error>> {
+        use std::io::Read;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.zip");
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("readme.txt".to_string(), 0, "Hello".as_bytes().to_vec()))?;
+        pak.add_file(PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![0, 1, 2, 3]))?;
+        pak.add_file(PakFileEntry::new("maps/".to_string(), 0, Vec::new()))?;
+        pak.add_file(PakFileEntry::new("sound/".to_string(), 0, Vec::new()))?;
+        pak.export_zip(path.to_str().unwrap().to_string())?;
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&path)?)?;
+        assert_eq!(zip.len(), 4);
+        assert!(zip.by_name("maps/").unwrap().is_dir());
+        assert!(zip.by_name("sound/").unwrap().is_dir());
+
+        let mut contents = String::new();
+        zip.by_name("readme.txt")?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "Hello");
+
+        let mut bsp = Vec::new();
+        zip.by_name("maps/e1m1.bsp")?.read_to_end(&mut bsp)?;
+        assert_eq!(bsp, vec![0, 1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_from_zip() -> Result<(), Box<dyn Error>> {
+        use std::io::Write;
+        use zip::write::SimpleFileOptions;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.zip");
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path)?);
+        zip.add_directory("maps/", SimpleFileOptions::default())?;
+        zip.start_file("maps/e1m1.bsp", SimpleFileOptions::default())?;
+        zip.write_all(&[0, 1, 2, 3])?;
+        zip.start_file("readme.txt", SimpleFileOptions::default())?;
+        zip.write_all(b"Hello")?;
+        zip.finish()?;
+
+        let pak = Pak::from_zip(path.to_str().unwrap().to_string())?;
+        let names: Vec<&str> = pak.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["maps/e1m1.bsp", "readme.txt"]);
+        assert_eq!(pak.files[0].size, 4);
+        assert_eq!(pak.files[1].size, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_aligned() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A'; 3]))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B'; 17]))?;
+        pak.add_file(PakFileEntry::new("c.txt".to_string(), 0, vec![b'C'; 1]))?;
+        pak.save_aligned(path.clone(), 16)?;
+
+        let pak = Pak::from_file(path)?;
+        assert_eq!(pak.files.len(), 3);
+        for file in pak.files.iter() {
+            assert_eq!(file.offset % 16, 0, "{} is not aligned", file.name);
+        }
+        assert_eq!(pak.files[1].size, 17);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_write_data() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let entry = pak.find("fastdl.txt").unwrap();
+
+        let mut buf: Vec<u8> = Vec::new();
+        entry.write_data(&mut buf)?;
+        assert_eq!(buf.len(), entry.size as usize);
+        assert!(String::from_utf8(buf)?.starts_with("http://files2.hldm.org/hl/\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_planned_layout() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("planned.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::from_file("extras.pak".to_string())?;
+        pak.remove_file("fastdl.txt".to_string())?;
+        pak.add_file(PakFileEntry::new("new.txt".to_string(), 0, b"new".to_vec()))?;
+        pak.entry_mut("credits.txt").unwrap().data_mut().extend_from_slice(b"more");
+
+        for last in [false, true] {
+            pak.set_file_table_offset_last(last);
+            let planned: Vec<(String, u64, u64)> =
+                pak.planned_layout().map(|(name, offset, size)| (name.to_string(), offset, size)).collect();
+            pak.save(path.clone())?;
+            let saved = Pak::from_file(path.clone())?;
+            let actual: Vec<(String, u64, u64)> =
+                saved.files.iter().map(|f| (f.name.clone(), f.offset as u64, f.size as u64)).collect();
+            assert_eq!(planned, actual);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_clone_is_independent() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let mut copy = pak.clone();
+        assert!(copy.files[0].shares_buffer_with(&pak.files[0]));
+
+        copy.entry_mut("fastdl.txt").unwrap().data_mut().clear();
+        copy.remove_file("credits.txt".to_string())?;
+        copy.header.offset = 0;
+        assert!(!copy.find("fastdl.txt").unwrap().shares_buffer_with(pak.find("fastdl.txt").unwrap()));
+
+        let original = Pak::from_file("extras.pak".to_string())?;
+        assert_eq!(pak.files.len(), original.files.len());
+        assert_eq!(pak.header.offset, original.header.offset);
+        assert_eq!(pak.find("fastdl.txt").unwrap().data(), original.find("fastdl.txt").unwrap().data());
+        assert!(pak.find("credits.txt").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn watch_repacks_after_debounce() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("maps"))?;
+        std::fs::write(src.join("maps/e1m1.bsp"), [1, 2, 3])?;
+        let pakfile = dir.path().join("out.pak").to_str().unwrap().to_string();
+
+        let mut repacker = Repacker::new(&src, pakfile.clone(), Duration::from_millis(500));
+        assert_eq!(repacker.repack()?, 1);
+
+        std::fs::write(src.join("maps/e1m1.bsp"), [4, 5])?;
+        std::fs::write(src.join("readme.txt"), b"hi")?;
+        let start = Instant::now();
+        repacker.changed(start);
+        repacker.changed(start + Duration::from_millis(300));
+        assert!(repacker.poll(start + Duration::from_millis(600)).is_none());
+        assert_eq!(repacker.poll(start + Duration::from_millis(800)).unwrap()?, 2);
+        assert!(repacker.poll(start + Duration::from_millis(2000)).is_none());
+
+        let pak = Pak::from_file(pakfile)?;
+        assert_eq!(pak.entry_names().collect::<Vec<_>>(), vec!["maps/e1m1.bsp", "readme.txt"]);
+        assert_eq!(pak.find("maps/e1m1.bsp").unwrap().data(), &[4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entries_by_disk_order() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![1; 4]))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![2; 4]))?;
+        pak.add_file(PakFileEntry::new("c.txt".to_string(), 0, vec![3; 4]))?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ordered.pak").to_str().unwrap().to_string();
+        pak.save_ordered(path.clone(), &["c.txt", "a.txt", "b.txt"])?;
+        let pak = Pak::from_file(path)?;
+
+        let ordered = pak.entries_by_disk_order();
+        assert_eq!(ordered.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["c.txt", "a.txt", "b.txt"]);
+        assert!(ordered.windows(2).all(|w| w[0].offset < w[1].offset));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pak_modes_sidecar() -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        let mut entry = PakFileEntry::new("tools/build.sh".to_string(), 0, b"#!/bin/sh\n".to_vec());
+        entry.mode = Some(0o755);
+        pak.add_file(entry)?;
+        pak.add_file(PakFileEntry::new("readme.txt".to_string(), 0, b"hi".to_vec()))?;
+        pak.save(path.clone())?;
+        pak.write_modes_sidecar(format!("{}.modes", path))?;
+        assert_eq!(std::fs::read_to_string(format!("{}.modes", path))?, "755\ttools/build.sh\n");
+
+        let pak = Pak::from_file(path)?;
+        assert_eq!(pak.find("readme.txt").unwrap().mode, None);
+        let out = dir.path().join("out");
+        pak.extract_to_dir(&out, |_| true, false)?;
+        let mode = std::fs::metadata(out.join("tools/build.sh"))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        // Special bits from the sidecar are dropped.
+        std::fs::write(format!("{}.modes", pak.pak_path), "4777\treadme.txt\n")?;
+        let pak = Pak::from_file(pak.pak_path.clone())?;
+        assert_eq!(pak.find("readme.txt").unwrap().mode, Some(0o777));
+        let mut entry = pak.find("readme.txt").unwrap().clone();
+        entry.mode = Some(0o6777);
+        let out = dir.path().join("setuid.txt");
+        entry.save_to(out.to_str().unwrap().to_string(), true, false)?;
+        assert_eq!(std::fs::metadata(&out)?.permissions().mode() & 0o7777, 0o777);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_find_by_sha256() -> Result<(), Box<dyn Error>> {
+        use sha2::{Digest, Sha256};
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.wav".to_string(), 0, b"same".to_vec()))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, b"other".to_vec()))?;
+        pak.add_file(PakFileEntry::new("copy/a.wav".to_string(), 0, b"same".to_vec()))?;
+
+        let same: [u8; 32] = Sha256::digest(b"same").into();
+        let names: Vec<&str> = pak.find_by_sha256(&same).iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["a.wav", "copy/a.wav"]);
+        assert!(pak.find_by_sha256(&[0; 32]).is_empty());
+
+        // The index follows later changes.
+        pak.entry_mut("b.txt").unwrap().data_mut().truncate(0);
+        pak.entry_mut("b.txt").unwrap().data_mut().extend_from_slice(b"same");
+        pak.remove_file("a.wav".to_string())?;
+        let names: Vec<&str> = pak.find_by_sha256(&same).iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["b.txt", "copy/a.wav"]);
+
+        // Replacing data twice can reuse the freed buffer; the index still follows.
+        for _ in 0..20 {
+            assert_eq!(pak.find_by_sha256(&same).len(), 2);
+            pak.replace_file("b.txt", vec![2; 64])?;
+            pak.replace_file("b.txt", vec![3; 64])?;
+            let threes: [u8; 32] = Sha256::digest([3; 64]).into();
+            assert_eq!(pak.find_by_sha256(&threes).len(), 1);
+            pak.replace_file("b.txt", b"same".to_vec())?;
+        }
+        pak.retain(|f| f.name != "copy/a.wav");
+        assert_eq!(pak.find_by_sha256(&same).len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn shell_scripted_session() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let outfile = dir.path().join("b.bsp");
+        let shell = Shell::new(Pak::from_bytes(&two_file_pak_bytes()?)?);
+        let script = [
+            "ls".to_string(),
+            "  ".to_string(),
+            "cat a.txt".to_string(),
+            "find maps/*".to_string(),
+            format!("extract maps/b.bsp {}", outfile.display()),
+            "ls maps/".to_string(),
+        ];
+
+        let mut out: Vec<u8> = Vec::new();
+        for line in script.iter() {
+            assert_eq!(shell.execute(line, &mut out)?, Step::Continue);
+        }
+        let expected = format!(
+            "a.txt - 5 bytes\nmaps/b.bsp - 3 bytes\nHellomaps/b.bsp\nExtracted 'maps/b.bsp' to '{}'\nmaps/b.bsp - 3 bytes\n",
+            outfile.display()
+        );
+        assert_eq!(String::from_utf8(out)?, expected);
+        assert_eq!(std::fs::read(&outfile)?, [1, 2, 3]);
+
+        let mut out: Vec<u8> = Vec::new();
+        assert!(shell.execute("cat missing.txt", &mut out).is_err());
+        assert!(shell.execute("cat", &mut out).is_err());
+        assert!(shell.execute("frobnicate", &mut out).is_err());
+        assert!(out.is_empty());
+        assert_eq!(shell.execute("quit", &mut out)?, Step::Quit);
+
+        // Without an outfile the entry's own name must stay relative.
+        let mut pak = Pak::empty();
+        pak.files.push(PakFileEntry::new("../evil.txt".to_string(), 0, b"evil".to_vec()));
+        let shell = Shell::new(pak);
+        assert!(matches!(
+            shell.execute("extract ../evil.txt", &mut out).map_err(|e| e.downcast::<PakError>().map(|e| *e)),
+            Err(Ok(PakError::InvalidName(_)))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_patch_round_trip() -> Result<(), Box<dyn Error>> {
+        let old = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        let mut new = old.clone();
+        new.replace_file("a.txt", b"Hello, world".to_vec())?;
+        new.take_file("maps/b.bsp");
+        new.add_file(PakFileEntry::new("maps/c.bsp".to_string(), 0, vec![4, 5, 6]))?;
+
+        let patch = old.diff_to_patch(&new);
+        assert_eq!(patch.added, vec![("maps/c.bsp".to_string(), vec![4, 5, 6])]);
+        assert_eq!(patch.changed, vec![("a.txt".to_string(), b"Hello, world".to_vec())]);
+        assert_eq!(patch.removed, vec!["maps/b.bsp".to_string()]);
+
+        let bytes = patch.to_bytes();
+        assert_eq!(&bytes[..4], PakPatch::MAGIC);
+        let patch = PakPatch::from_bytes(&bytes)?;
+        let mut patched = old.clone();
+        patched.apply_patch(&patch)?;
+        assert!(patched.logically_eq(&new));
+        assert_eq!(old.diff_to_patch(&old).to_bytes().len(), 4);
+
+        // Applying twice fails and leaves the Pak alone.
+        assert!(matches!(patched.apply_patch(&patch), Err(PakError::NotFound(_))));
+        assert!(patched.logically_eq(&new));
+        assert!(matches!(PakPatch::from_bytes(&bytes[..bytes.len() - 1]), Err(PakError::Corrupt(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_write_table_record() -> Result<(), Box<dyn Error>> {
+        let entry = PakFileEntry::new("maps/e1m1.bsp".to_string(), 0x1234, vec![0; 300]);
+        let mut record: Vec<u8> = Vec::new();
+        entry.write_table_record(&mut record)?;
+
+        assert_eq!(record.len(), 64);
+        assert_eq!(&record[..13], b"maps/e1m1.bsp");
+        assert!(record[13..56].iter().all(|&b| b == 0));
+        assert_eq!(&record[56..60], &0x1234u32.to_le_bytes());
+        assert_eq!(&record[60..], &300u32.to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn pak_entry_deprecated_writers() -> Result<(), Box<dyn Error>> {
+        let entry = PakFileEntry::new("a.txt".to_string(), 12, b"Hello".to_vec());
+        let (mut old, mut new): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+        entry.write_to(&mut old)?;
+        entry.write_table_record(&mut new)?;
+        assert_eq!(old, new);
+
+        let (mut old, mut new): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+        entry.write_data_to(&mut old)?;
+        entry.write_data(&mut new)?;
+        assert_eq!(old, new);
+        Ok(())
+    }
+
+    fn two_file_pak_bytes() -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, "Hello".as_bytes().to_vec()))?;
+        pak.add_file(PakFileEntry::new("maps/b.bsp".to_string(), 0, vec![1, 2, 3]))?;
+        let mut bytes: Vec<u8> = Vec::new();
+        pak.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn pak_from_reader() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_reader(std::io::Cursor::new(two_file_pak_bytes()?))?;
+        assert_eq!(pak.files.len(), 2);
+        assert_eq!(pak.files[0].name, "a.txt");
+        assert_eq!(pak.files[1].size, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_from_reader_at_base_offset() -> Result<(), Box<dyn Error>> {
+        let mut bytes = vec![0xAA; 100];
+        bytes.extend(two_file_pak_bytes()?);
+        let mut cursor = std::io::Cursor::new(bytes);
+        cursor.set_position(100);
+
+        let pak = Pak::from_reader(cursor)?;
+        assert_eq!(pak.files.len(), 2);
+        let mut data: Vec<u8> = Vec::new();
+        pak.find("a.txt").unwrap().write_data(&mut data)?;
+        assert_eq!(data, "Hello".as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_over_max_size() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak");
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A'; 100]))?;
+        pak.max_size = Some(150);
+
+        let err = pak.save(path.to_str().unwrap().to_string()).unwrap_err();
+        // 12 + 64 + 100 = 176 bytes
+        assert!(err.to_string().contains("26 bytes over"), "{}", err);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_under_max_size() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak");
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A'; 100]))?;
+        pak.max_size = Some(176);
+        pak.save(path.to_str().unwrap().to_string())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 176);
+
+        // Alignment padding counts against the limit too.
+        assert!(pak.save_aligned(path.to_str().unwrap().to_string(), 16).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_content_hash_ignores_order() -> Result<(), Box<dyn Error>> {
+        let mut a = Pak::empty();
+        a.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A']))?;
+        a.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B']))?;
+
+        let mut b = Pak::empty();
+        b.add_file(PakFileEntry::new("b.txt".to_string(), 99, vec![b'B']))?;
+        b.add_file(PakFileEntry::new("a.txt".to_string(), 12, vec![b'A']))?;
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        let mut c = Pak::empty();
+        c.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A']))?;
+        c.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'C']))?;
+        assert_ne!(a.content_hash(), c.content_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_deduplicated() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let naive = dir.path().join("naive.pak").to_str().unwrap().to_string();
+        let dedup = dir.path().join("dedup.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A'; 1000]))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B'; 10]))?;
+        pak.add_file(PakFileEntry::new("copy_of_a.txt".to_string(), 0, vec![b'A'; 1000]))?;
+        pak.save(naive.clone())?;
+        pak.save_deduplicated(dedup.clone())?;
+
+        assert_eq!(
+            std::fs::metadata(&naive)?.len() - std::fs::metadata(&dedup)?.len(),
+            1000
+        );
+
+        let reloaded = Pak::from_file(dedup)?;
+        assert_eq!(reloaded.files[0].offset, reloaded.files[2].offset);
+        assert_eq!(reloaded.content_hash(), pak.content_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_name_is_nul_terminated() -> Result<(), Box<dyn Error>> {
+        let name = "x".repeat(55);
+        let entry = PakFileEntry::new(name.clone(), 0, vec![]);
+        let mut record: Vec<u8> = Vec::new();
+        entry.write_table_record(&mut record)?;
+
+        assert_eq!(record.len(), 64);
+        assert_eq!(&record[..55], name.as_bytes());
+        assert_eq!(record[55], 0);
+
+        let too_long = PakFileEntry::new("x".repeat(56), 0, vec![]);
+        assert!(too_long.write_table_record(&mut Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_rebuild_removes_gaps() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B'; 3]))?;
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A'; 5]))?;
+        pak.save_aligned(path.clone(), 16)?;
+
+        let messy = Pak::from_file(path)?;
+        assert!(!messy.gaps().is_empty());
+        messy.verify()?;
+
+        let rebuilt = messy.rebuild()?;
+        assert!(rebuilt.gaps().is_empty());
+        rebuilt.verify()?;
+        assert_eq!(rebuilt.files[0].name, "a.txt");
+        assert_eq!(rebuilt.files[0].offset, 12 + 2 * 64);
+        assert_eq!(rebuilt.files[1].offset, 12 + 2 * 64 + 5);
+        assert_eq!(rebuilt.content_hash(), messy.content_hash());
+
+        let mut big = messy.clone();
+        big.endian = Endian::Big;
+        big.set_file_table_offset_last(true);
+        let rebuilt = big.rebuild()?;
+        assert_eq!(rebuilt.endian, Endian::Big);
+        assert_eq!(rebuilt.files[0].offset, 12);
+        assert_eq!(rebuilt.header.offset, 12 + 5 + 3);
+        assert_eq!(big.filter(|_| true).endian, Endian::Big);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_times_sidecar() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        let mut entry = PakFileEntry::new("a.txt".to_string(), 0, vec![b'A']);
+        entry.mtime = Some(1_600_000_000);
+        pak.add_file(entry)?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B']))?;
+        pak.save(path.clone())?;
+        pak.write_times_sidecar(format!("{}.times", path))?;
+
+        let pak = Pak::from_file(path)?;
+        assert_eq!(pak.find("b.txt").unwrap().mtime, None);
+        let out = dir.path().join("out/a.txt");
+        pak.find("a.txt")
+            .unwrap()
+            .save_to(out.to_str().unwrap().to_string(), true, false)?;
+
+        let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(&out)?);
+        assert_eq!(mtime.unix_seconds(), 1_600_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_filter_prefix() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let sub = pak.filter(|f| f.name.starts_with("touch_default/"));
+        assert_eq!(sub.files.len(), 35);
+        assert!(sub.files.iter().all(|f| f.name.starts_with("touch_default/")));
+        assert_eq!(pak.files.len(), 139);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_empty() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("empty.pak").to_str().unwrap().to_string();
+
+        Pak::empty().save(path.clone())?;
+        let bytes = std::fs::read(&path)?;
+        assert_eq!(bytes, b"PACK\x0c\0\0\0\0\0\0\0");
+
+        let pak = Pak::from_file(path.clone())?;
+        assert!(pak.files.is_empty());
+        assert_eq!(pak.header.offset, 12);
+        assert_eq!(pak.header.size, 0);
+
+        pak.save(path.clone())?;
+        assert_eq!(std::fs::read(&path)?, bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_load_header_only() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("empty.pak");
+
+        // A table offset past the end is fine as long as the table is empty.
+        std::fs::write(&path, b"PACK\xff\0\0\0\0\0\0\0")?;
+        let pak = Pak::from_file(path.to_str().unwrap().to_string())?;
+        assert!(pak.files.is_empty());
+
+        let pak = Pak::from_reader(std::fs::File::open(&path)?)?;
+        assert!(pak.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_data_range() -> Result<(), Box<dyn Error>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let entry = pak.find("fastdl.txt").unwrap();
+        let range = pak.entry_data_range("fastdl.txt").unwrap();
+        assert_eq!(range, entry.offset as u64..(entry.offset + entry.size) as u64);
+        assert!(pak.entry_data_range("doesnotexist.txt").is_none());
+
+        let mut f = std::fs::File::open("extras.pak")?;
+        f.seek(SeekFrom::Start(range.start))?;
+        let mut data = vec![0; (range.end - range.start) as usize];
+        f.read_exact(&mut data)?;
+        assert!(data.starts_with(b"http://"));
+        Ok(())
+    }
+
+    fn assert_corrupt(result: Result<Pak, Box<dyn Error>>, message: &str) {
+        match result.map_err(|e| e.downcast::<PakError>()) {
+            Err(Ok(e)) => match *e {
+                PakError::Corrupt(msg) => assert!(msg.contains(message), "{}", msg),
+                e => panic!("unexpected error: {}", e),
+            },
+            Err(Err(e)) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("corrupt Pak was accepted"),
+        }
+    }
+
+    #[test]
+    fn pak_table_offset_zero_is_corrupt() -> Result<(), Box<dyn Error>> {
+        let mut bytes = two_file_pak_bytes()?;
+        bytes[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert_corrupt(
+            Pak::from_reader(std::io::Cursor::new(bytes.clone())),
+            "file table overlaps the header",
+        );
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak");
+        std::fs::write(&path, bytes)?;
+        assert_corrupt(
+            Pak::from_file(path.to_str().unwrap().to_string()),
+            "file table overlaps the header",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pak_table_overlapping_data_is_corrupt() -> Result<(), Box<dyn Error>> {
+        let mut bytes = two_file_pak_bytes()?;
+        // Point the second entry's data into the first table record.
+        let record = 12 + 64;
+        bytes[record + 56..record + 60].copy_from_slice(&20u32.to_le_bytes());
+        assert_corrupt(
+            Pak::from_reader(std::io::Cursor::new(bytes)),
+            "maps/b.bsp overlaps the file table",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pak_table_size_not_multiple_of_64_is_corrupt() -> Result<(), Box<dyn Error>> {
+        for size in [65u32, 63] {
+            let mut bytes = two_file_pak_bytes()?;
+            bytes[8..12].copy_from_slice(&size.to_le_bytes());
+            let message = format!("file table size {} is not a multiple of 64", size);
+            assert_corrupt(Pak::from_bytes(&bytes).map_err(Into::into), &message);
+            assert_corrupt(Pak::from_reader(std::io::Cursor::new(bytes)), &message);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_data_in_header_is_corrupt() -> Result<(), Box<dyn Error>> {
+        let mut bytes = two_file_pak_bytes()?;
+        let record = 12 + 64;
+        bytes[record + 56..record + 60].copy_from_slice(&4u32.to_le_bytes());
+        assert_corrupt(Pak::from_bytes(&bytes).map_err(Into::into), "maps/b.bsp overlaps the header");
+        assert_corrupt(
+            Pak::from_reader(std::io::Cursor::new(bytes)),
+            "maps/b.bsp overlaps the header",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pak_insert_file_at() -> Result<(), Box<dyn Error>> {
+        let (mut pak, _) = merge_fixture();
+        pak.insert_file_at(0, PakFileEntry::new("first.txt".to_string(), 0, vec![b'1']))?;
+        let names: Vec<&str> = pak.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["first.txt", "a.txt", "b.txt"]);
+
+        assert!(matches!(
+            pak.insert_file_at(1, PakFileEntry::new("b.txt".to_string(), 0, vec![])),
+            Err(PakError::Duplicate(_))
+        ));
+        assert!(matches!(
+            pak.insert_file_at(4, PakFileEntry::new("last.txt".to_string(), 0, vec![])),
+            Err(PakError::IndexOutOfRange(4))
+        ));
+        pak.insert_file_at(3, PakFileEntry::new("last.txt".to_string(), 0, vec![]))?;
+        assert_eq!(pak.files[3].name, "last.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_names_checked_everywhere() -> Result<(), Box<dyn Error>> {
+        let (mut pak, _) = merge_fixture();
+        assert!(matches!(
+            pak.insert_file_at(0, PakFileEntry::new("../evil".to_string(), 0, vec![])),
+            Err(PakError::InvalidName(_))
+        ));
+        assert!(matches!(pak.rename_prefix("a", "../a"), Err(PakError::InvalidName(_))));
+        assert_eq!(pak.files[0].name, "a.txt");
+
+        pak.insert_file_at(0, PakFileEntry::new("sub\\./c.txt".to_string(), 0, vec![]))?;
+        assert_eq!(pak.files[0].name, "sub/c.txt");
+
+        pak.ascii_names_only = true;
+        assert!(matches!(
+            pak.insert_file_at(0, PakFileEntry::new("caf\u{e9}.txt".to_string(), 0, vec![])),
+            Err(PakError::InvalidName(_))
+        ));
+        assert!(matches!(pak.rename_prefix("a", "\u{e9}"), Err(PakError::InvalidName(_))));
+
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("caf\u{e9}.txt"), b"")?;
+        assert!(matches!(pak.append_dir(dir.path(), dir.path()), Err(PakError::InvalidName(_))));
+
+        pak.allow_duplicates = true;
+        pak.insert_file_at(0, PakFileEntry::new("b.txt".to_string(), 0, vec![]))?;
+        assert_eq!(pak.files.iter().filter(|f| f.name == "b.txt").count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_from_file_verified() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let mut expected: std::collections::HashMap<String, u32> =
+            pak.files.iter().map(|f| (f.name.clone(), f.crc32())).collect();
+        let verified = Pak::from_file_verified("extras.pak".to_string(), &expected)?;
+        assert_eq!(verified.files.len(), 139);
+
+        *expected.get_mut("fastdl.txt").unwrap() ^= 1;
+        match Pak::from_file_verified("extras.pak".to_string(), &expected) {
+            Err(PakError::Corrupt(msg)) => assert!(msg.starts_with("fastdl.txt has CRC32"), "{}", msg),
+            other => panic!("expected a CRC mismatch, got {:?}", other.map(|p| p.files.len())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_big_endian_round_trip() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("be.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, "Hello".as_bytes().to_vec()))?;
+        pak.add_file(PakFileEntry::new("maps/b.bsp".to_string(), 0, vec![1, 2, 3]))?;
+        pak.endian = Endian::Big;
+        pak.save(path.clone())?;
+
+        let bytes = std::fs::read(&path)?;
+        assert_eq!(&bytes[4..12], &[0, 0, 0, 12, 0, 0, 0, 128]);
+        assert_eq!(Endian::detect(&bytes), Some(Endian::Big));
+
+        let err = Pak::from_file(path.clone()).unwrap_err();
+        assert!(err.to_string().contains("looks big-endian"), "{}", err);
+
+        let reloaded = Pak::from_file_with_endian(path, Endian::Big)?;
+        assert_eq!(reloaded.endian, Endian::Big);
+        assert_eq!(reloaded.files[1].name, "maps/b.bsp");
+        assert_eq!(reloaded.files[1].offset, 12 + 128 + 5);
+        assert_eq!(reloaded.content_hash(), pak.content_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_debug_is_bounded() {
+        let entry = PakFileEntry::new("big.bin".to_string(), 0, vec![0xAB; 1 << 20]);
+        let debug = format!("{:?}", entry);
+        assert!(debug.len() < 200, "{}", debug);
+        assert!(debug.contains("name: \"big.bin\""));
+        assert!(debug.contains("size: 1048576"));
+        assert!(debug.contains("data: abababababababababababababababab…"));
+
+        let small = PakFileEntry::new("small.txt".to_string(), 0, vec![b'H', b'i']);
+        assert!(format!("{:?}", small).contains("data: 4869 }"));
+    }
+
+    #[test]
+    fn pak_name_length_warnings() -> Result<(), Box<dyn Error>> {
+        let long_name = format!("{}.txt", "x".repeat(50));
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new(long_name.clone(), 0, vec![]))?;
+        pak.add_file(PakFileEntry::new("short.txt".to_string(), 0, vec![]))?;
+
+        assert_eq!(pak.name_length_warnings(50), vec![(long_name.as_str(), 54)]);
+        assert!(pak.name_length_warnings(55).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_add_empty() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_empty("marker".to_string())?;
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A']))?;
+        // Ends up with its offset at the very end of the file.
+        pak.add_empty("maps/marker".to_string())?;
+        pak.save(path.clone())?;
+
+        let pak = Pak::from_file(path.clone())?;
+        assert_eq!(pak.files.len(), 3);
+        assert_eq!(pak.files[0].size, 0);
+        assert_eq!(pak.files[2].size, 0);
+        assert_eq!(pak.files[2].offset as u64, std::fs::metadata(&path)?.len());
+        pak.verify()?;
+        Ok(())
+    }
+
+    #[test]
+    fn pak_rename_prefix() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("textures/wall.tga".to_string(), 0, vec![]))?;
+        pak.add_file(PakFileEntry::new("textures/sky/up.tga".to_string(), 0, vec![]))?;
+        pak.add_file(PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![]))?;
+
+        assert_eq!(pak.rename_prefix("textures/", "tex/")?, 2);
+        let names: Vec<&str> = pak.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["tex/wall.tga", "tex/sky/up.tga", "maps/e1m1.bsp"]);
+
+        // Collisions and over-long names leave everything untouched.
+        pak.add_file(PakFileEntry::new("maps/wall.tga".to_string(), 0, vec![]))?;
+        assert!(matches!(pak.rename_prefix("tex/", "maps/"), Err(PakError::Duplicate(_))));
+        assert!(matches!(
+            pak.rename_prefix("tex/", &"x/".repeat(25)),
+            Err(PakError::InvalidName(_))
+        ));
+        assert_eq!(pak.files[0].name, "tex/wall.tga");
+        Ok(())
+    }
+
+    fn listing_of(format: ListFormat, files: &[PakFileEntry]) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        Listing::new(format).write(&mut out, files.iter()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn listing_formats() {
+        let files = vec![
+            PakFileEntry::new("weird, \"name\".txt".to_string(), 76, vec![0; 3]),
+            PakFileEntry::new("maps/e1m1.bsp".to_string(), 79, vec![0; 5]),
+        ];
+
+        let plain = listing_of(ListFormat::Plain, &files);
+        assert_eq!(plain.lines().next(), Some("weird, \"name\".txt - 3 bytes"));
+
+        let csv = listing_of(ListFormat::Csv, &files);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("name,offset,size"));
+        assert_eq!(lines.next(), Some("\"weird, \"\"name\"\".txt\",76,3"));
+        assert_eq!(lines.next(), Some("maps/e1m1.bsp,79,5"));
+
+        let json = listing_of(ListFormat::Json, &files);
+        let mut lines = json.lines();
+        assert_eq!(lines.next(), Some("["));
+        assert_eq!(
+            lines.next(),
+            Some("  {\"name\": \"weird, \\\"name\\\".txt\", \"offset\": 76, \"size\": 3},")
+        );
+        assert_eq!(json.lines().last(), Some("]"));
+    }
+
+    #[test]
+    fn pak_with_capacity() {
+        let mut pak = Pak::with_capacity(1000);
+        assert!(pak.files.capacity() >= 1000);
+        assert!(pak.files.is_empty());
+
+        pak.reserve(5000);
+        assert!(pak.files.capacity() >= 5000);
+    }
+
+    #[test]
+    fn pak_load_shares_one_buffer() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let first = &pak.files[0];
+        assert!(pak.files.iter().all(|f| f.shares_buffer_with(first)));
+        assert_eq!(pak.find("fastdl.txt").unwrap().data().len(), 171);
+
+        let added = PakFileEntry::new("new.txt".to_string(), 0, vec![1, 2, 3]);
+        assert!(!added.shares_buffer_with(first));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_load_mutate_save_keeps_data() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("mutated.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::from_file("extras.pak".to_string())?;
+        pak.remove_file("credits.txt".to_string())?;
+        pak.add_file(PakFileEntry::new("new.txt".to_string(), 0, b"new".to_vec()))?;
+        let fastdl = pak.find("fastdl.txt").unwrap();
+        assert!(fastdl.shares_buffer_with(&pak.files[0]));
+        pak.save(out.clone())?;
+
+        let saved = Pak::from_file(out)?;
+        assert_eq!(saved.files.len(), 139);
+        assert!(saved.find("credits.txt").is_none());
+        assert_eq!(saved.find("new.txt").unwrap().data(), b"new");
+        assert_eq!(saved.find("fastdl.txt").unwrap().crc32(), 0xe178f232);
+        Ok(())
+    }
+
+    #[test]
+    fn pakfileentry_from_path() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("autoexec.cfg");
+        std::fs::write(&path, b"bind w +forward\n")?;
+
+        let entry = PakFileEntry::from_path("cfg/autoexec.cfg".to_string(), &path)?;
+        assert_eq!(entry.name, "cfg/autoexec.cfg");
+        assert_eq!(entry.offset, 0);
+        assert_eq!(entry.size as u64, std::fs::metadata(&path)?.len());
+        assert_eq!(entry.data(), b"bind w +forward\n");
+        assert!(entry.mtime.is_some());
+
+        assert!(PakFileEntry::from_path("x".to_string(), dir.path().join("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_extract_to_dir_matching() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("cfg/autoexec.cfg".to_string(), 0, b"exec".to_vec()))?;
+        pak.add_file(PakFileEntry::new("server.cfg".to_string(), 0, b"sv".to_vec()))?;
+        pak.add_file(PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![1, 2, 3]))?;
+
+        let written = pak.extract_to_dir(dir.path(), |f| f.extension() == Some("cfg"), false)?;
+        assert_eq!(written, 2);
+        assert_eq!(std::fs::read(dir.path().join("cfg/autoexec.cfg"))?, b"exec");
+        assert_eq!(std::fs::read(dir.path().join("server.cfg"))?, b"sv");
+        assert!(!dir.path().join("maps").exists());
+
+        assert!(pak.extract_to_dir(dir.path(), |_| true, false).is_err());
+        assert_eq!(pak.extract_to_dir(dir.path(), |_| true, true)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_extract_to_dir_rejects_traversal() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("out");
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("ok.txt".to_string(), 0, b"ok".to_vec()))?;
+        // add_file won't take the name, so plant it the way a hostile Pak would.
+        pak.files.push(PakFileEntry::new("../evil.txt".to_string(), 0, b"evil".to_vec()));
+
+        match pak.extract_to_dir(&out, |_| true, false) {
+            Err(PakError::InvalidName(name)) => assert_eq!(name, "../evil.txt"),
+            other => panic!("expected InvalidName, got {:?}", other),
+        }
+        assert!(!dir.path().join("evil.txt").exists());
+        assert!(!out.join("ok.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_extract_to_dir_long_names() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        // A name filling the whole field, with no NUL after it.
+        let field = [b'n'; 56];
+        let pak = Pak::from_bytes(&pak_with_raw_name(&field))?;
+        assert_eq!(pak.extract_to_dir(dir.path(), |_| true, false)?, 1);
+        assert_eq!(std::fs::read(dir.path().join("n".repeat(56)))?, b"hi");
+
+        for name in ["/abs.txt", "dir\\win.txt", "c:evil.txt", "a/../../evil.txt"] {
+            let mut pak = Pak::empty();
+            pak.files.push(PakFileEntry::new(name.to_string(), 0, Vec::new()));
+            assert!(matches!(pak.extract_to_dir(dir.path(), |_| true, false), Err(PakError::InvalidName(_))), "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pakfileentry_stripped_name() {
+        let entry = PakFileEntry::new("a/b/c.txt".to_string(), 0, Vec::new());
+        assert_eq!(entry.stripped_name(0), Some("a/b/c.txt"));
+        assert_eq!(entry.stripped_name(1), Some("b/c.txt"));
+        assert_eq!(entry.stripped_name(2), Some("c.txt"));
+        assert_eq!(entry.stripped_name(3), None);
+    }
+
+    #[test]
+    fn pak_raw_header_and_table_bytes() -> Result<(), Box<dyn Error>> {
+        let bytes = std::fs::read("extras.pak")?;
+        let pak = Pak::from_file("extras.pak".to_string())?;
+
+        let header = pak.header.as_bytes();
+        assert_eq!(&header[..], &bytes[..12]);
+        let reparsed = rustpak::PakHeader::from_u8(&header)?;
+        assert_eq!(reparsed.id, pak.header.id);
+        assert_eq!(reparsed.offset, pak.header.offset);
+        assert_eq!(reparsed.size, pak.header.size);
+
+        let table = pak.raw_table_bytes();
+        let start = pak.header.offset as usize;
+        assert_eq!(table.len(), pak.header.size as usize);
+        assert_eq!(&table[..], &bytes[start..start + table.len()]);
+        assert_eq!(pak.raw_header_bytes(), header);
+
+        // Big-endian Paks get both in their own byte order.
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("big.pak").to_str().unwrap().to_string();
+        let mut big = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        big.endian = Endian::Big;
+        big.save(path.clone())?;
+        let bytes = std::fs::read(&path)?;
+        let big = Pak::from_file_with_endian(path, Endian::Big)?;
+        assert_eq!(&big.raw_header_bytes()[..], &bytes[..12]);
+        assert_ne!(big.header.as_bytes(), big.raw_header_bytes());
+        let start = big.header.offset as usize;
+        assert_eq!(&big.raw_table_bytes()[..], &bytes[start..start + big.header.size as usize]);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_huge_table_size_is_corrupt() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("huge.pak").to_str().unwrap().to_string();
+        let mut bytes = two_file_pak_bytes()?;
+        bytes[8..12].copy_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+
+        assert_corrupt(Pak::from_file(path), "file table runs past the end");
+        assert_corrupt(Pak::from_reader(std::io::Cursor::new(bytes)), "file table runs past the end");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_from_bytes_never_panics() -> Result<(), Box<dyn Error>> {
+        let valid = std::fs::read("extras.pak")?;
+        assert_eq!(Pak::from_bytes(&valid)?.files.len(), 139);
+
+        // xorshift, so the inputs are the same on every run.
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for i in 0..1000 {
+            let len = (next() % 512) as usize;
+            let mut bytes: Vec<u8> = if i % 2 == 0 {
+                (0..len).map(|_| next() as u8).collect()
+            } else {
+                // Truncated real Paks with a few bytes flipped.
+                let mut bytes = valid[..len.min(valid.len())].to_vec();
+                if !bytes.is_empty() {
+                    for _ in 0..4 {
+                        let at = (next() as usize) % bytes.len();
+                        bytes[at] = next() as u8;
+                    }
+                }
+                bytes
+            };
+            if i % 3 == 0 && bytes.len() >= 12 {
+                // Headers that point somewhere plausible get further into the parser.
+                bytes[..4].copy_from_slice(b"PACK");
+                let offset = 12 + (next() % 64) as u32;
+                let size = 64 * (next() % 8) as u32;
+                bytes[4..8].copy_from_slice(&offset.to_le_bytes());
+                bytes[8..12].copy_from_slice(&size.to_le_bytes());
+            }
+            let _ = Pak::from_bytes(&bytes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_trailer_round_trip() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trailer.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        pak.set_trailer(b"built by rustpak".to_vec());
+        pak.save(path.clone())?;
+
+        assert_eq!(Pak::read_trailer(path.clone())?, b"built by rustpak");
+        let loaded = Pak::from_file(path)?;
+        assert_eq!(loaded.files.len(), 2);
+        assert_eq!(loaded.find("a.txt").unwrap().data(), b"Hello");
+        assert!(loaded.trailer().is_empty());
+
+        assert!(Pak::read_trailer("extras.pak".to_string())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_non_ascii_names() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("sound/ok.wav".to_string(), 0, Vec::new()))?;
+        pak.add_file(PakFileEntry::new("sound/café.wav".to_string(), 0, Vec::new()))?;
+        assert_eq!(pak.non_ascii_names(), vec!["sound/café.wav"]);
+
+        pak.ascii_names_only = true;
+        let err = pak
+            .add_file(PakFileEntry::new("maps/ü.bsp".to_string(), 0, Vec::new()))
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<PakError>(), Some(PakError::InvalidName(_))));
+        pak.add_file(PakFileEntry::new("maps/u.bsp".to_string(), 0, Vec::new()))?;
+        assert_eq!(pak.files.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_ordered() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("ordered.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, b"aa".to_vec()))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, b"bbb".to_vec()))?;
+        pak.add_file(PakFileEntry::new("c.txt".to_string(), 0, b"c".to_vec()))?;
+        pak.save_ordered(path.clone(), &["c.txt", "a.txt"])?;
+
+        let saved = Pak::from_file(path.clone())?;
+        let names: Vec<&str> = saved.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "b.txt", "c.txt"]);
+        let data_start = 12 + 3 * 64;
+        assert_eq!(saved.find("c.txt").unwrap().offset, data_start);
+        assert_eq!(saved.find("a.txt").unwrap().offset, data_start + 1);
+        assert_eq!(saved.find("b.txt").unwrap().offset, data_start + 3);
+        assert_eq!(saved.find("b.txt").unwrap().data(), b"bbb");
+        saved.verify()?;
+
+        assert!(pak.save_ordered(path, &["missing.txt"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_matching() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        assert_eq!(pak.matching("touch_default/**")?.count(), 35);
+        let txt: Vec<&str> = pak.matching("*.txt")?.map(|f| f.name.as_str()).collect();
+        assert!(txt.contains(&"fastdl.txt"));
+        assert!(txt.iter().all(|name| !name.contains('/')));
+
+        assert!(matches!(pak.matching("a[").err(), Some(PakError::InvalidPattern(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_take_file_moves_entry() -> Result<(), Box<dyn Error>> {
+        let mut from = Pak::from_file("extras.pak".to_string())?;
+        let mut to = Pak::empty();
+
+        let entry = from.take_file("fastdl.txt").unwrap();
+        assert_eq!(from.files.len(), 138);
+        assert!(from.find("fastdl.txt").is_none());
+        assert!(from.take_file("fastdl.txt").is_none());
+
+        to.add_file(entry)?;
+        assert_eq!(to.find("fastdl.txt").unwrap().crc32(), 0xe178f232);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_logically_eq() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+
+        let mut reordered = Pak::empty();
+        reordered.add_file(PakFileEntry::new("maps/b.bsp".to_string(), 500, vec![1, 2, 3]))?;
+        reordered.add_file(PakFileEntry::new("a.txt".to_string(), 12, b"Hello".to_vec()))?;
+        assert!(pak.logically_eq(&reordered));
+        assert!(reordered.logically_eq(&pak));
+
+        let mut changed = reordered.filter(|_| true);
+        changed.take_file("maps/b.bsp");
+        changed.add_file(PakFileEntry::new("maps/b.bsp".to_string(), 0, vec![1, 2, 4]))?;
+        assert!(!pak.logically_eq(&changed));
+
+        changed.take_file("maps/b.bsp");
+        assert!(!pak.logically_eq(&changed));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_append_in_place() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("big.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("big1.bin".to_string(), 0, vec![0xAA; 256 * 1024]))?;
+        pak.add_file(PakFileEntry::new("big2.bin".to_string(), 0, vec![0xBB; 256 * 1024]))?;
+        pak.save(path.clone())?;
+        let before = std::fs::read(&path)?;
+
+        Pak::append_in_place(path.clone(), "small.txt".to_string(), b"small".to_vec())?;
+        let after = std::fs::read(&path)?;
+        // Only the header's table fields changed; the rest was appended.
+        assert_eq!(&after[..4], &before[..4]);
+        assert_eq!(&after[12..before.len()], &before[12..]);
+        assert_eq!(after.len(), before.len() + 5 + 3 * 64);
+
+        // The table is now last, so the next append writes over it.
+        Pak::append_in_place(path.clone(), "tiny.txt".to_string(), b"t".to_vec())?;
+        assert_eq!(std::fs::read(&path)?.len(), after.len() + 1 + 64);
+
+        let loaded = Pak::from_file(path.clone())?;
+        let names: Vec<&str> = loaded.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["big1.bin", "big2.bin", "small.txt", "tiny.txt"]);
+        assert_eq!(loaded.find("small.txt").unwrap().data(), b"small");
+        assert_eq!(loaded.find("big2.bin").unwrap().data(), &vec![0xBB; 256 * 1024][..]);
+        loaded.verify()?;
+
+        assert!(Pak::append_in_place(path.clone(), "tiny.txt".to_string(), Vec::new()).is_err());
+
+        let size = std::fs::read(&path)?.len();
+        assert!(Pak::append_in_place(path.clone(), "../evil.txt".to_string(), Vec::new()).is_err());
+        assert_eq!(std::fs::read(&path)?.len(), size);
+        Pak::append_in_place(path.clone(), "dir\\win.txt".to_string(), b"w".to_vec())?;
+        assert_eq!(Pak::from_file(path)?.files[4].name, "dir/win.txt");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_detected_alignment() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("aligned.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![1; 5]))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![2; 3]))?;
+        pak.add_file(PakFileEntry::new("c.txt".to_string(), 0, vec![3; 7]))?;
+        pak.save_aligned(path.clone(), 4)?;
+
+        let loaded = Pak::from_file(path)?;
+        assert!(loaded.files.iter().all(|f| f.offset % 4 == 0));
+        assert_eq!(loaded.detected_alignment(), Some(4));
+
+        assert_eq!(Pak::from_file("extras.pak".to_string())?.detected_alignment(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_into_map() -> Result<(), Box<dyn Error>> {
+        let map = Pak::from_file("extras.pak".to_string())?.into_map();
+        assert_eq!(map.len(), 139);
+        assert!(map["fastdl.txt"].starts_with(b"http://files2.hldm.org/hl/\n"));
+
+        let mut pak = Pak::empty();
+        pak.files.push(PakFileEntry::new("dup.txt".to_string(), 0, b"first".to_vec()));
+        pak.files.push(PakFileEntry::new("dup.txt".to_string(), 0, b"second".to_vec()));
+        assert_eq!(pak.into_map()["dup.txt"], b"second");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_from_map() -> Result<(), Box<dyn Error>> {
+        let mut map: std::collections::HashMap<String, Vec<u8>> = std::collections::HashMap::new();
+        map.insert("sound/b.wav".to_string(), vec![2; 4]);
+        map.insert("a.cfg".to_string(), b"exec".to_vec());
+        map.insert("maps/c.bsp".to_string(), vec![3; 9]);
+
+        let pak = Pak::from_map(map.clone())?;
+        let names: Vec<&str> = pak.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["a.cfg", "maps/c.bsp", "sound/b.wav"]);
+        assert_eq!(pak.find("a.cfg").unwrap().data(), b"exec");
+        assert_eq!(pak.into_map(), map);
+
+        map.insert("x".repeat(56), Vec::new());
+        assert!(matches!(Pak::from_map(map), Err(PakError::InvalidName(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_split_by_size() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        for name in ["a.bin", "b.bin", "c.bin"] {
+            pak.add_file(PakFileEntry::new(name.to_string(), 0, vec![0; 100]))?;
+        }
+
+        // Room for two entries per part: 12 + 2 * (64 + 100) = 340.
+        let parts = pak.split_by_size(340)?;
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].files.len(), 2);
+        assert_eq!(parts[1].files.len(), 1);
+        let mut names: Vec<&str> = parts.iter().flat_map(|p| p.files.iter()).map(|f| f.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["a.bin", "b.bin", "c.bin"]);
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("part.pak").to_str().unwrap().to_string();
+        parts[0].save(path.clone())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), 340);
+
+        assert!(matches!(pak.split_by_size(175), Err(PakError::TooLarge(_))));
+
+        let mut bytes = two_file_pak_bytes()?;
+        bytes[..4].copy_from_slice(b"SPAK");
+        let mut spak = Pak::from_bytes(&bytes)?;
+        spak.endian = Endian::Big;
+        spak.set_file_table_offset_last(true);
+        for part in spak.split_by_size(12 + 64 + 5)?.iter_mut() {
+            assert_eq!(part.magic(), b"SPAK");
+            assert_eq!(part.endian, Endian::Big);
+            part.compact();
+            assert_eq!(part.files[0].offset, 12);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_split_then_join() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let parts = pak.split_by_size(3_000_000)?;
+        assert!(parts.len() > 1);
+
+        let joined = Pak::join(parts, ConflictPolicy::Error)?;
+        assert!(joined.logically_eq(&pak));
+
+        let mut bytes = two_file_pak_bytes()?;
+        bytes[..4].copy_from_slice(b"SPAK");
+        let mut spak = Pak::from_bytes(&bytes)?;
+        spak.set_file_table_offset_last(true);
+        let mut joined = Pak::join(spak.split_by_size(12 + 64 + 5)?, ConflictPolicy::Error)?;
+        assert_eq!(joined.magic(), b"SPAK");
+        joined.compact();
+        assert_eq!(joined.files[0].offset, 12);
+        assert_eq!(Pak::join(Vec::new(), ConflictPolicy::Error)?.magic(), rustpak::MAGIC);
+
+        let (base, other) = merge_fixture();
+        assert!(matches!(
+            Pak::join(vec![base, other], ConflictPolicy::Error),
+            Err(PakError::Duplicate(name)) if name == "b.txt"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn pakfileentry_is_text() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        assert!(pak.find("touch_default/numbers.cfg").unwrap().is_text());
+        assert!(pak.find("fastdl.txt").unwrap().is_text());
+        assert!(!pak.find("gfx/shell/btns_main.bmp").unwrap().is_text());
+
+        let bsp = PakFileEntry::new("maps/e1m1.bsp".to_string(), 0, vec![29, 0, 0, 0, 124, 0, 0, 0]);
+        assert!(!bsp.is_text());
+
+        let mut listing = Listing::new(ListFormat::Csv);
+        listing.kind = true;
+        let mut out: Vec<u8> = Vec::new();
+        listing.write(&mut out, [&bsp])?;
+        assert_eq!(String::from_utf8(out)?, "name,offset,size,kind\nmaps/e1m1.bsp,0,8,binary\n");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_names_keep_whitespace() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("spaces.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new(" weird.txt".to_string(), 0, b"w".to_vec()))?;
+        pak.add_file(PakFileEntry::new("trailing.txt ".to_string(), 0, b"t".to_vec()))?;
+        pak.save(path.clone())?;
+
+        let loaded = Pak::from_file(path)?;
+        assert_eq!(loaded.files[0].name, " weird.txt");
+        assert_eq!(loaded.find(" weird.txt").unwrap().data(), b"w");
+        assert!(loaded.find("weird.txt").is_none());
+        assert_eq!(loaded.files[1].name, "trailing.txt ");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_header_too_short() {
+        match rustpak::PakHeader::from_u8(b"PACK\x0c") {
+            Err(PakError::Corrupt(msg)) => assert_eq!(msg, "file too small for header"),
+            other => panic!("expected Corrupt, got {:?}", other),
+        }
+        assert!(matches!(Pak::from_bytes(b"PACK\x0c"), Err(PakError::Corrupt(_))));
+    }
+
+    #[test]
+    fn pak_extract_all_parallel() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        assert_eq!(pak.extract_all_parallel(dir.path(), 4, false)?, 139);
+
+        for file in pak.files.iter() {
+            assert_eq!(std::fs::read(dir.path().join(&file.name))?, file.data(), "{}", file.name);
+        }
+        assert!(pak.extract_all_parallel(dir.path(), 4, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_layout_covers_file() -> Result<(), Box<dyn Error>> {
+        let bytes = two_file_pak_bytes()?;
+        let pak = Pak::from_bytes(&bytes)?;
+        let layout = pak.layout();
+
+        let kinds: Vec<RegionKind> = layout.iter().map(|r| r.kind).collect();
+        assert!(kinds.contains(&RegionKind::Header) && kinds.contains(&RegionKind::Table));
+        assert_eq!(kinds.iter().filter(|&&k| k == RegionKind::Data).count(), 2);
+
+        let mut covered = 0;
+        for region in layout.iter() {
+            assert_eq!(region.start, covered, "{:?}", region);
+            covered = region.end;
+        }
+        assert_eq!(covered, bytes.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_with_layout() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let first = dir.path().join("first.pak").to_str().unwrap().to_string();
+        let last = dir.path().join("last.pak").to_str().unwrap().to_string();
+        let pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        pak.save_with_layout(first.clone(), TableLayout::First)?;
+        pak.save_with_layout(last.clone(), TableLayout::Last)?;
+
+        assert_eq!(std::fs::metadata(&last)?.len(), 12 + 8 + 2 * 64);
+        let first = Pak::from_file(first)?;
+        let last = Pak::from_file(last)?;
+        assert_eq!(first.header.offset, 12);
+        assert_eq!(last.header.offset, 12 + 5 + 3);
+        assert_eq!(last.files[0].offset, 12);
+        assert!(first.logically_eq(&last));
+        last.verify()?;
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_table_last() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("last.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::from_file("extras.pak".to_string())?;
+        pak.set_file_table_offset_last(true);
+        pak.save(path.clone())?;
+
+        let len = std::fs::metadata(&path)?.len();
+        assert_eq!(len, pak.estimated_save_size(None));
+        let mut loaded = Pak::from_file(path.clone())?;
+        assert_eq!(loaded.header.offset as u64 + loaded.header.size as u64, len);
+        assert!(loaded.files.iter().all(|f| f.offset < loaded.header.offset));
+        assert!(loaded.logically_eq(&pak));
+        loaded.verify()?;
+
+        pak.compact();
+        assert_eq!(pak.header.offset, loaded.header.offset);
+
+        // Rewriting a loaded table-last Pak keeps the table last.
+        loaded.remove_file("credits.txt".to_string())?;
+        loaded.save_in_place(path.clone())?;
+        let rewritten = Pak::from_file(path.clone())?;
+        assert!(rewritten.files.iter().all(|f| f.offset < rewritten.header.offset));
+        assert_eq!(rewritten.header.offset as u64 + rewritten.header.size as u64, std::fs::metadata(&path)?.len());
+        assert!(Pak::from_reader(std::fs::File::open(&path)?)?.rebuild()?.files[0].offset < rewritten.header.offset);
+        // A table right after the header stays there.
+        let first = Pak::from_bytes(&two_file_pak_bytes()?)?.rebuild()?;
+        assert_eq!(first.header.offset, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_replace_file_from_path() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("update.pak").to_str().unwrap().to_string();
+        let source = dir.path().join("new_a.txt");
+        std::fs::write(&source, b"Goodbye!")?;
+
+        let mut pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        pak.replace_file_from_path("a.txt", &source)?;
+        pak.save(path.clone())?;
+
+        let loaded = Pak::from_file(path)?;
+        assert_eq!(loaded.files[0].name, "a.txt");
+        assert_eq!(loaded.files[0].data(), b"Goodbye!");
+        assert_eq!(loaded.files[0].size, 8);
+        assert_eq!(loaded.find("maps/b.bsp").unwrap().data(), &[1, 2, 3]);
+
+        assert!(matches!(pak.replace_file_from_path("nope.txt", &source), Err(PakError::NotFound(_))));
+        assert!(matches!(
+            pak.replace_file_from_path("a.txt", dir.path().join("missing")),
+            Err(PakError::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_append_dir_relative_names() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let assets = dir.path().join("assets");
+        std::fs::create_dir_all(assets.join("maps"))?;
+        std::fs::write(assets.join("maps/e1m1.bsp"), [1, 2, 3])?;
+        std::fs::write(assets.join("autoexec.cfg"), b"exec")?;
+
+        assert_eq!(
+            PakFileEntry::name_relative_to(assets.join("maps/e1m1.bsp"), &assets)?,
+            "maps/e1m1.bsp"
+        );
+        assert!(PakFileEntry::name_relative_to(dir.path().join("other.txt"), &assets).is_err());
+
+        let mut pak = Pak::empty();
+        assert_eq!(pak.append_dir(assets.join("maps"), &assets)?, 1);
+        assert_eq!(pak.append_dir(&assets, dir.path())?, 2);
+        let names: Vec<&str> = pak.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["maps/e1m1.bsp", "assets/autoexec.cfg", "assets/maps/e1m1.bsp"]);
+
+        assert!(matches!(pak.append_dir(&assets, &assets), Err(PakError::Duplicate(_))));
+        assert_eq!(pak.files.len(), 3);
+        Ok(())
+    }
+
+    fn pak_with_raw_name(name_field: &[u8; 56]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(b"PACK");
+        bytes.extend_from_slice(&12u32.to_le_bytes());
+        bytes.extend_from_slice(&64u32.to_le_bytes());
+        bytes.extend_from_slice(name_field);
+        bytes.extend_from_slice(&76u32.to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(b"hi");
+        bytes
+    }
+
+    #[test]
+    fn pak_nul_padded_name() -> Result<(), Box<dyn Error>> {
+        let mut field = [0u8; 56];
+        field[..11].copy_from_slice(b" maps/a.bsp");
+        // Whatever follows the NUL is garbage, spaces included.
+        field[12..15].copy_from_slice(b"x  ");
+        let pak = Pak::from_bytes(&pak_with_raw_name(&field))?;
+        assert_eq!(pak.files[0].name, " maps/a.bsp");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_space_padded_name() -> Result<(), Box<dyn Error>> {
+        let mut field = [b' '; 56];
+        field[..11].copy_from_slice(b" maps/a.bsp");
+        let pak = Pak::from_bytes(&pak_with_raw_name(&field))?;
+        assert_eq!(pak.files[0].name, " maps/a.bsp");
+        assert_eq!(pak.files[0].data(), b"hi");
+
+        let full = [b'x'; 56];
+        let pak = Pak::from_bytes(&pak_with_raw_name(&full))?;
+        assert_eq!(pak.files[0].name.len(), 56);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_checksum_manifest() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        let manifest = pak.checksum_manifest(HashAlgo::Sha256);
+        assert_eq!(
+            manifest["a.txt"],
+            "185f8db32271fe25f561a6fc938b2e264306ec304eda518007d1764826381969"
+        );
+        let names: Vec<&String> = manifest.keys().collect();
+        assert_eq!(names, vec!["a.txt", "maps/b.bsp"]);
+
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        assert_eq!(pak.checksum_manifest(HashAlgo::Crc32)["fastdl.txt"], "e178f232");
+        assert_eq!(
+            pak.checksum_manifest(HashAlgo::Sha1)["fastdl.txt"],
+            "57bdded4cf2a856d60b9629a812149272758b9d0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pak_estimated_save_size() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        assert_eq!(pak.estimated_save_size(None), 12);
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, vec![b'A'; 3]))?;
+        pak.add_file(PakFileEntry::new("b.txt".to_string(), 0, vec![b'B'; 17]))?;
+        assert_eq!(pak.estimated_save_size(None), 12 + 2 * 64 + 20);
+
+        pak.save(path.clone())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), pak.estimated_save_size(None));
+        pak.save_aligned(path.clone(), 16)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), pak.estimated_save_size(Some(16)));
+
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        pak.save_aligned(path.clone(), 64)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), pak.estimated_save_size(Some(64)));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_reader_data_for() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak");
+        std::fs::write(&path, two_file_pak_bytes()?)?;
+
+        let mut reader = PakReader::open(&path)?;
+        assert_eq!(reader.entries().len(), 2);
+        assert_eq!(reader.entries()[1].name, "maps/b.bsp");
+        assert_eq!(reader.reads(), 0);
+
+        assert_eq!(reader.data_for("a.txt")?, b"Hello");
+        assert_eq!(reader.data_for("maps/b.bsp")?, &[1, 2, 3]);
+        assert_eq!(reader.reads(), 2);
+        assert_eq!(reader.data_for("a.txt")?, b"Hello");
+        assert_eq!(reader.reads(), 2);
+
+        assert!(matches!(reader.data_for("missing.txt"), Err(PakError::NotFound(_))));
+
+        let mut bytes = vec![0xAA; 100];
+        bytes.extend(two_file_pak_bytes()?);
+        let mut cursor = std::io::Cursor::new(bytes);
+        cursor.set_position(100);
+        let mut reader = PakReader::new(cursor)?;
+        assert_eq!(reader.data_for("maps/b.bsp")?, &[1, 2, 3]);
+
+        // A size past the end of the file is caught before anything is read.
+        let mut bytes = two_file_pak_bytes()?;
+        let table = u32::from_le_bytes(bytes[4..8].try_into()?) as usize;
+        bytes[table + 60..table + 64].copy_from_slice(&u32::MAX.to_le_bytes());
+        match PakReader::new(std::io::Cursor::new(bytes)) {
+            Err(PakError::Corrupt(msg)) => assert_eq!(msg, "data of a.txt runs past the end of the file"),
+            other => panic!("expected Corrupt, got {:?}", other.map(|r| r.entries().len())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pak_entry_data_mut() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("edit.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+
+        let data = pak.entry_mut("a.txt").unwrap().data_mut();
+        data[0] = b'J';
+        data.extend_from_slice(b", world");
+        assert!(pak.entry_mut("missing.txt").is_none());
+        // The other entry still points into the loaded buffer.
+        assert_eq!(pak.files[1].data(), &[1, 2, 3]);
+
+        pak.save(path.clone())?;
+        assert_eq!(std::fs::metadata(&path)?.len(), pak.estimated_save_size(None));
+        let loaded = Pak::from_file(path)?;
+        assert_eq!(loaded.files[0].size, 12);
+        assert_eq!(loaded.files[0].data(), b"Jello, world");
+        assert_eq!(loaded.files[1].data(), &[1, 2, 3]);
+        loaded.verify()?;
+        Ok(())
+    }
+
+    #[test]
+    fn pak_try_walk_stops_early() -> Result<(), Box<dyn Error>> {
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        let mut total = 0;
+        pak.walk(|f| total += f.size as u64);
+        assert_eq!(total, pak.files.iter().map(|f| f.size as u64).sum::<u64>());
+
+        let mut visits = 0;
+        let result = pak.try_walk(|f| {
+            visits += 1;
+            if f.name == pak.files[4].name {
+                return Err(f.name.clone());
+            }
+            Ok(())
+        });
+        assert_eq!(result, Err(pak.files[4].name.clone()));
+        assert_eq!(visits, 5);
+
+        let mut visits = 0;
+        pak.try_walk(|_| {
+            visits += 1;
+            Ok::<(), PakError>(())
+        })?;
+        assert_eq!(visits, 139);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_sanitize_name() -> Result<(), Box<dyn Error>> {
+        assert_eq!(Pak::sanitize_name("maps/e1m1.bsp")?, "maps/e1m1.bsp");
+        assert_eq!(Pak::sanitize_name("sound\\misc//./hit.wav")?, "sound/misc/hit.wav");
+        assert_eq!(Pak::sanitize_name("maps/")?, "maps/");
+        assert_eq!(Pak::sanitize_name(&"x".repeat(55))?, "x".repeat(55));
+
+        for bad in ["../evil.txt", "maps/../../evil.txt", "/etc/passwd", "\\evil.txt", "C:evil.txt", "", "./"] {
+            assert!(matches!(Pak::sanitize_name(bad), Err(PakError::InvalidName(name)) if name == bad), "{}", bad);
+        }
+        assert!(matches!(Pak::sanitize_name(&"x".repeat(56)), Err(PakError::InvalidName(_))));
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("gfx\\conback.lmp".to_string(), 0, vec![1]))?;
+        assert_eq!(pak.files[0].name, "gfx/conback.lmp");
+        assert!(pak.add_file(PakFileEntry::new("gfx/conback.lmp".to_string(), 0, vec![2])).is_err());
+        let err = pak.add_file(PakFileEntry::new("../evil.txt".to_string(), 0, vec![])).unwrap_err();
+        assert!(matches!(err.downcast_ref::<PakError>(), Some(PakError::InvalidName(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_allow_duplicates() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dupes.pak").to_str().unwrap().to_string();
+
+        let mut pak = Pak::empty();
+        pak.add_file(PakFileEntry::new("progs.dat".to_string(), 0, b"old".to_vec()))?;
+        assert!(pak.add_file(PakFileEntry::new("progs.dat".to_string(), 0, b"new".to_vec())).is_err());
+        pak.allow_duplicates = true;
+        pak.add_file(PakFileEntry::new("progs.dat".to_string(), 0, b"new".to_vec()))?;
+        pak.save(path.clone())?;
+
+        let loaded = Pak::from_file(path)?;
+        assert_eq!(loaded.files.len(), 2);
+        assert_eq!(loaded.files[0].data(), b"old");
+        assert_eq!(loaded.files[1].name, "progs.dat");
+        assert_eq!(loaded.files[1].data(), b"new");
+        assert_eq!(loaded.find("progs.dat").unwrap().data(), b"old");
+        Ok(())
+    }
+
+    #[test]
+    fn pak_layout_constants() -> Result<(), Box<dyn Error>> {
+        assert_eq!(rustpak::ENTRY_SIZE, rustpak::NAME_SIZE + 8);
+        let bytes = two_file_pak_bytes()?;
+        assert_eq!(&bytes[..4], rustpak::MAGIC);
+        assert_eq!(bytes.len(), rustpak::HEADER_SIZE + 2 * rustpak::ENTRY_SIZE + 8);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_sync_to_dir() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        assert_eq!(pak.sync_to_dir(dir.path())?, (139, 0));
+        assert_eq!(pak.sync_to_dir(dir.path())?, (0, 139));
+
+        std::fs::write(dir.path().join("fastdl.txt"), b"edited")?;
+        std::fs::remove_file(dir.path().join("credits.txt"))?;
+        let untouched = std::fs::metadata(dir.path().join("font0_cp1251.fnt"))?.modified()?;
+        assert_eq!(pak.sync_to_dir(dir.path())?, (2, 137));
+        assert_eq!(std::fs::read(dir.path().join("fastdl.txt"))?, pak.find("fastdl.txt").unwrap().data());
+        assert!(dir.path().join("credits.txt").exists());
+        assert_eq!(std::fs::metadata(dir.path().join("font0_cp1251.fnt"))?.modified()?, untouched);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_canonicalize_names() -> Result<(), Box<dyn Error>> {
+        let mut field = [0u8; 56];
+        field[..17].copy_from_slice(b"./maps/e1m1.bsp\0\0");
+        let mut pak = Pak::from_bytes(&pak_with_raw_name(&field))?;
+        assert!(pak.find("maps/e1m1.bsp").is_none());
+
+        assert_eq!(pak.canonicalize_names()?, 1);
+        assert_eq!(pak.find("maps/e1m1.bsp").unwrap().data(), b"hi");
+        assert_eq!(pak.canonicalize_names()?, 0);
+
+        pak.files.push(PakFileEntry::new("./maps/e1m1.bsp".to_string(), 0, Vec::new()));
+        assert!(matches!(pak.canonicalize_names(), Err(PakError::Duplicate(_))));
+        assert_eq!(pak.files[1].name, "./maps/e1m1.bsp");
+
+        let mut pak = Pak::empty();
+        for name in ["./maps/./e1m1.bsp", "sound/./x.wav"] {
+            pak.files.push(PakFileEntry::new(name.to_string(), 0, Vec::new()));
+        }
+        assert_eq!(pak.canonicalize_names()?, 1);
+        assert_eq!(pak.entry_names().collect::<Vec<_>>(), ["maps/./e1m1.bsp", "sound/./x.wav"]);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_save_atomic() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("atomic.pak").to_str().unwrap().to_string();
+        std::fs::write(&path, b"not a pak")?;
+
+        let pak = Pak::from_file("extras.pak".to_string())?;
+        pak.save_atomic(path.clone())?;
+
+        let saved = Pak::from_file(path)?;
+        saved.verify()?;
+        assert!(saved.logically_eq(&pak));
+        // Only the target is left behind, no temp files.
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_retain() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::from_file("extras.pak".to_string())?;
+        let txt = pak.files.iter().filter(|f| f.extension() == Some("txt")).count();
+        assert!(txt > 0 && txt < pak.files.len());
+
+        pak.retain(|f| f.extension() == Some("txt"));
+        assert_eq!(pak.files.len(), txt);
+        assert!(pak.files.iter().all(|f| f.name.ends_with(".txt")));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_alternative_magic() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let spak = dir.path().join("spak.pak").to_str().unwrap().to_string();
+        let mut bytes = two_file_pak_bytes()?;
+        bytes[..4].copy_from_slice(b"SPAK");
+        std::fs::write(&spak, &bytes)?;
+
+        assert_corrupt(Pak::from_file_with_magics(spak.clone(), &[rustpak::MAGIC]), "unexpected magic 'SPAK'");
+        let pak = Pak::from_file_with_magics(spak.clone(), &[b"PACK", b"SPAK"])?;
+        assert_eq!(pak.magic(), b"SPAK");
+        assert_eq!(pak.files[0].data(), b"Hello");
+
+        let saved = dir.path().join("saved.pak").to_str().unwrap().to_string();
+        pak.save(saved.clone())?;
+        assert_eq!(std::fs::read(&saved)?, bytes);
+
+        // Paks loaded the usual way keep their magic too, also when rewritten.
+        let mut pak = Pak::from_file(spak.clone())?;
+        assert_eq!(pak.magic(), b"SPAK");
+        pak.remove_file("a.txt".to_string())?;
+        pak.save_in_place(spak.clone())?;
+        assert_eq!(&std::fs::read(&spak)?[..4], b"SPAK");
+        assert_eq!(Pak::from_reader(std::io::Cursor::new(bytes))?.magic(), b"SPAK");
+        assert_eq!(Pak::empty().magic(), rustpak::MAGIC);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_size_stats() -> Result<(), Box<dyn Error>> {
+        let mut pak = Pak::empty();
+        assert_eq!(pak.size_stats().median, 0.0);
+        for (i, size) in [10usize, 2000, 300, 40000, 500_000].iter().enumerate() {
+            pak.add_file(PakFileEntry::new(format!("{}.bin", i), 0, vec![0; *size]))?;
+        }
+
+        let stats = pak.size_stats();
+        assert_eq!(stats.files, 5);
+        assert_eq!(stats.total_bytes, 542_310);
+        assert_eq!((stats.min, stats.max), (10, 500_000));
+        assert_eq!(stats.mean, 108_462.0);
+        assert_eq!(stats.median, 2000.0);
+        assert_eq!(stats.histogram, [2, 1, 1, 1]);
+
+        pak.add_file(PakFileEntry::new("6.bin".to_string(), 0, vec![0; 1024]))?;
+        let stats = pak.size_stats();
+        assert_eq!(stats.median, 1512.0);
+        assert_eq!(stats.histogram, [2, 2, 1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn pak_from_gzip_file() -> Result<(), Box<dyn Error>> {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.pak.gz").to_str().unwrap().to_string();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&std::fs::read("extras.pak")?)?;
+        std::fs::write(&path, encoder.finish()?)?;
+
+        let pak = Pak::from_file(path.clone())?;
+        let plain = Pak::from_file("extras.pak".to_string())?;
+        assert_eq!(pak.files.len(), 139);
+        assert!(pak.logically_eq(&plain));
+
+        // Rewriting in place would drop the compression, so it's refused.
+        let compressed = std::fs::read(&path)?;
+        assert!(pak.save_in_place(path.clone()).is_err());
+        assert!(pak.rebuild()?.save_atomic(path.clone()).is_err());
+        assert_eq!(std::fs::read(&path)?, compressed);
+        Ok(())
+    }
+
+    #[test]
+    fn pak_repair_scan() -> Result<(), Box<dyn Error>> {
+        let mut bytes = std::fs::read("extras.pak")?;
+        let original = Pak::from_bytes(&bytes)?;
+        bytes[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(Pak::from_bytes(&bytes).is_err());
+
+        let repaired = Pak::repair_scan(&bytes);
+        assert_eq!(repaired.files.len(), original.files.len());
+        assert!(repaired.logically_eq(&original));
+
+        let mut bytes = two_file_pak_bytes()?;
+        bytes[..12].fill(0);
+        let repaired = Pak::repair_scan(&bytes);
+        assert_eq!(repaired.find("maps/b.bsp").unwrap().data(), &[1, 2, 3]);
+
+        assert!(Pak::repair_scan(&[0xAB; 500]).files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pak_io_errors_are_pak_errors() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        // A regular file where the output directory should go.
+        let blocker = dir.path().join("blocker");
+        std::fs::write(&blocker, b"")?;
+
+        let pak = Pak::from_bytes(&two_file_pak_bytes()?)?;
+        match pak.extract_to_dir(blocker.join("out"), |_| true, false) {
+            Err(PakError::Io(_)) => {}
+            other => panic!("expected PakError::Io, got {:?}", other),
+        }
+        assert!(matches!(pak.sync_to_dir(&blocker), Err(PakError::Io(_))));
+
+        let e: PakError = std::io::Error::from(std::io::ErrorKind::NotFound).into();
+        assert!(matches!(e, PakError::Io(_)));
+        let e: PakError = String::from_utf8(vec![b'a', 0xff]).unwrap_err().into();
+        assert!(matches!(e, PakError::InvalidName(name) if name == "a\u{fffd}"));
+
+        let bytes = two_file_pak_bytes()?;
+        let record = &bytes[12..12 + 64];
+        assert_eq!(PakFileEntry::from_u8(record, &bytes)?.data(), b"Hello");
+        assert!(matches!(PakFileEntry::from_u8(record, &bytes[..80]), Err(PakError::Corrupt(_))));
+        assert!(matches!(PakFileEntry::from_u8(&record[..10], &bytes), Err(PakError::Corrupt(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn pak_open_or_create() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("new.pak").to_str().unwrap().to_string();
+        let mut pak = Pak::open_or_create(path.clone())?;
+        assert!(pak.files.is_empty());
+        assert_eq!(pak.pak_path, path);
+        assert!(!dir.path().join("new.pak").exists());
+
+        pak.add_file(PakFileEntry::new("a.txt".to_string(), 0, b"a".to_vec()))?;
+        pak.save(path.clone())?;
+        assert_eq!(Pak::open_or_create(path)?.files.len(), 1);
+
+        let corrupt = dir.path().join("corrupt.pak").to_str().unwrap().to_string();
+        std::fs::write(&corrupt, b"PACK")?;
+        assert!(matches!(Pak::open_or_create(corrupt), Err(PakError::Corrupt(_))));
+        Ok(())
+    }
 }